@@ -14,16 +14,6 @@ ALTER SYSTEM SET pii_vault.cache_ttl_sec = 300;
 -- Reload configuration
 SELECT pg_reload_conf();
 
--- Create helper function for integer to bytea conversion
-CREATE OR REPLACE FUNCTION int_to_key_bytes(id INTEGER) RETURNS BYTEA AS $$
-    SELECT decode(lpad(to_hex(id), 8, '0'), 'hex')
-$$ LANGUAGE SQL IMMUTABLE;
-
--- Create helper function for bigint to bytea conversion
-CREATE OR REPLACE FUNCTION bigint_to_key_bytes(id BIGINT) RETURNS BYTEA AS $$
-    SELECT decode(lpad(to_hex(id), 16, '0'), 'hex')
-$$ LANGUAGE SQL IMMUTABLE;
-
 -- Create demo table
 CREATE TABLE IF NOT EXISTS users_demo (
     id INTEGER PRIMARY KEY,
@@ -33,9 +23,9 @@ CREATE TABLE IF NOT EXISTS users_demo (
 
 -- Insert demo data (encrypted)
 INSERT INTO users_demo VALUES
-    (1, 'alice@example.com', piitext_encrypt('Alice secret password', int_to_key_bytes(1))),
-    (2, 'bob@example.com', piitext_encrypt('Bob confidential data', int_to_key_bytes(2))),
-    (3, 'charlie@example.com', piitext_encrypt('Charlie personal info', int_to_key_bytes(3)));
+    (1, 'alice@example.com', piitext_encrypt('Alice secret password', pii_key_id(1))),
+    (2, 'bob@example.com', piitext_encrypt('Bob confidential data', pii_key_id(2))),
+    (3, 'charlie@example.com', piitext_encrypt('Charlie personal info', pii_key_id(3)));
 
 -- Insert demo data (staging - unencrypted)
 INSERT INTO users_demo VALUES
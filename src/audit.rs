@@ -0,0 +1,275 @@
+// Decryption audit trail. Every decryption of a sealed or FPE value is recorded with
+// the current and session roles, session, client address, key_id, AAD labels, purpose and
+// outcome. Events are aggregated per statement (a SELECT over a million rows
+// of one subject is one event with count 1000000) and handed to the sink
+// configured in pii_vault.audit_sink when the transaction ends:
+//
+//   none    nothing is recorded (default)
+//   log     one structured line per event in the server log
+//   table   rows in pii_vault.audit_log, written at commit by the committing
+//           transaction itself rather than asynchronously, so that a crash
+//           cannot lose the events of a committed transaction; events of
+//           aborted and read-only transactions and of standbys go to the
+//           server log instead
+//   syslog  RFC 5424 datagrams to pii_vault.audit_syslog_address
+use crate::contents::PiiSealedData;
+use crate::{privileges, PII_VAULT_AUDIT_SINK, PII_VAULT_AUDIT_SYSLOG_ADDRESS};
+use once_cell::sync::{Lazy, OnceCell};
+use pgrx::prelude::*;
+use pgrx::{register_xact_callback, PgXactCallbackEvent};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::net::UdpSocket;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    // The plaintext was returned
+    Plaintext,
+    // Only a masked projection was returned
+    Masked,
+    // Decrypted to be sealed again (rotation, rebinding)
+    Resealed,
+    // The key was missing or the value did not authenticate
+    Failed,
+}
+
+impl Outcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Plaintext => "plaintext",
+            Outcome::Masked => "masked",
+            Outcome::Resealed => "resealed",
+            Outcome::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PostgresGucEnum)]
+pub enum Sink {
+    #[name = c"none"]
+    None,
+    #[name = c"log"]
+    Log,
+    #[name = c"table"]
+    Table,
+    #[name = c"syslog"]
+    Syslog,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+struct Event {
+    statement_start: pg_sys::TimestampTz,
+    role: String,
+    // The login role, which SET ROLE and SECURITY DEFINER functions leave alone
+    session_role: String,
+    session_id: String,
+    client_addr: Option<String>,
+    key_id: String,
+    relation: Option<String>,
+    attribute: Option<String>,
+    purpose: Option<String>,
+    outcome: Outcome,
+}
+
+// Events of the current transaction and their counts; the transaction end
+// callbacks are registered with the first event
+static PENDING: Lazy<Mutex<HashMap<Event, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn record(sealed: &PiiSealedData, outcome: Outcome) {
+    record_key(
+        &sealed.key_id,
+        sealed.relation.as_deref(),
+        sealed.attribute.as_deref(),
+        outcome,
+    );
+}
+
+// For decryptions of values that are not sealed envelopes (format-preserving
+// encryption), which carry no AAD labels
+pub fn record_key(
+    key_id: &[u8],
+    relation: Option<&str>,
+    attribute: Option<&str>,
+    outcome: Outcome,
+) {
+    if PII_VAULT_AUDIT_SINK.get() == Sink::None {
+        return;
+    }
+
+    let event = unsafe {
+        let (started, pid) = (pg_sys::MyStartTime, pg_sys::MyProcPid);
+        Event {
+            statement_start: pg_sys::GetCurrentStatementStartTimestamp(),
+            role: c_string(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), true))
+                .unwrap_or_default(),
+            session_role: c_string(pg_sys::GetUserNameFromId(pg_sys::GetSessionUserId(), true))
+                .unwrap_or_default(),
+            // Same format as %c in log_line_prefix
+            session_id: format!("{:x}.{:x}", started, pid),
+            client_addr: client_addr(),
+            key_id: hex::encode(key_id),
+            relation: relation.map(str::to_owned),
+            attribute: attribute.map(str::to_owned),
+            purpose: privileges::current_purpose(),
+            outcome,
+        }
+    };
+
+    let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+    if pending.is_empty() {
+        register_xact_callback(PgXactCallbackEvent::PreCommit, || flush(false));
+        register_xact_callback(PgXactCallbackEvent::Abort, || flush(true));
+    }
+    *pending.entry(event).or_insert(0) += 1;
+}
+
+// The client address does not change during a session; pg_sys::Port is opaque
+// to extensions, so it is asked once through SQL
+fn client_addr() -> Option<String> {
+    static CLIENT_ADDR: OnceCell<Option<String>> = OnceCell::new();
+    CLIENT_ADDR
+        .get_or_init(|| {
+            Spi::get_one::<String>("SELECT host(inet_client_addr())")
+                .ok()
+                .flatten()
+        })
+        .clone()
+}
+
+unsafe fn c_string(ptr: *const std::os::raw::c_char) -> Option<String> {
+    match ptr.is_null() {
+        true => None,
+        false => Some(CStr::from_ptr(ptr).to_string_lossy().into_owned()),
+    }
+}
+
+fn flush(aborted: bool) {
+    let sink = PII_VAULT_AUDIT_SINK.get();
+
+    // Events are only taken once written, so that a failed insert aborts the
+    // transaction and the abort callback still logs them. Read-only
+    // transactions and standbys cannot write the table and log instead.
+    let writable = unsafe { !pg_sys::XactReadOnly && !pg_sys::RecoveryInProgress() };
+    if sink == Sink::Table && !aborted && writable {
+        let events: Vec<(Event, i64)> = {
+            let pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+            pending.iter().map(|(e, c)| (e.clone(), *c)).collect()
+        };
+        write_table(&events);
+        PENDING.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        return;
+    }
+
+    let events: Vec<(Event, i64)> = {
+        let mut pending = PENDING.lock().unwrap_or_else(|e| e.into_inner());
+        pending.drain().collect()
+    };
+    match sink {
+        Sink::None => {}
+        Sink::Log | Sink::Table => {
+            for (event, count) in &events {
+                pgrx::log!("pii_vault audit: {}", event_json(event, *count));
+            }
+        }
+        Sink::Syslog => send_syslog(&events),
+    }
+}
+
+// Hand the events recorded so far in this transaction to the sink now, e.g.
+// from long-running transactions
+#[pg_extern(volatile)]
+fn pii_vault_audit_flush() {
+    flush(false);
+}
+
+fn event_json(event: &Event, count: i64) -> String {
+    let mut json = serde_json::to_value(event).expect("audit event serializes");
+    json["count"] = count.into();
+    json.to_string()
+}
+
+// Inserted as the owner of pii_vault.audit_log, which the audited roles cannot
+// write themselves
+fn write_table(events: &[(Event, i64)]) {
+    privileges::as_owner_of("pii_vault.audit_log", || {
+        for (event, count) in events {
+            Spi::run_with_args(
+                "INSERT INTO pii_vault.audit_log \
+                     (statement_start, role, session_role, session_id, client_addr, key_id, relation, attribute, purpose, outcome, events) \
+                 VALUES ('2000-01-01 00:00:00+00'::timestamptz + $1 * interval '1 microsecond', \
+                         $2, $3, $4, $5, decode($6, 'hex'), $7, $8, $9, $10, $11)",
+                &[
+                    event.statement_start.into(),
+                    event.role.clone().into(),
+                    event.session_role.clone().into(),
+                    event.session_id.clone().into(),
+                    event.client_addr.clone().into(),
+                    event.key_id.clone().into(),
+                    event.relation.clone().into(),
+                    event.attribute.clone().into(),
+                    event.purpose.clone().into(),
+                    event.outcome.as_str().into(),
+                    (*count).into(),
+                ],
+            )
+            .unwrap_or_else(|e| pgrx::error!("pii_vault: writing audit log failed: {}", e));
+        }
+    });
+}
+
+// Facility local0, severity informational; timestamp and hostname are left to
+// the collector
+fn send_syslog(events: &[(Event, i64)]) {
+    let address = PII_VAULT_AUDIT_SYSLOG_ADDRESS.get();
+    let address = address
+        .as_ref()
+        .map(|a| a.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "127.0.0.1:514".to_string());
+
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            pgrx::warning!("pii_vault: audit syslog socket: {}", e);
+            return;
+        }
+    };
+    let pid = unsafe { pg_sys::MyProcPid };
+    for (event, count) in events {
+        let message = format!(
+            "<134>1 - - pii_vault {} audit - {}",
+            pid,
+            event_json(event, *count)
+        );
+        if let Err(e) = socket.send_to(message.as_bytes(), &address) {
+            pgrx::warning!("pii_vault: audit syslog to {}: {}", address, e);
+            return;
+        }
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE pii_vault.audit_log (
+    id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    logged_at timestamptz NOT NULL DEFAULT now(),
+    statement_start timestamptz NOT NULL,
+    role name NOT NULL,
+    session_role name NOT NULL,
+    session_id text NOT NULL,
+    client_addr text,
+    key_id bytea NOT NULL,
+    relation text,
+    attribute text,
+    purpose text,
+    outcome text NOT NULL CHECK (outcome IN ('plaintext', 'masked', 'resealed', 'failed')),
+    events bigint NOT NULL
+);
+REVOKE ALL ON pii_vault.audit_log FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('pii_vault.audit_log', '');
+"#,
+    name = "audit_log",
+    requires = ["column_policy"]
+);
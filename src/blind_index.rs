@@ -0,0 +1,58 @@
+// Blind indexes: a keyed HMAC-SHA256 of the (normalized) plaintext, truncated to
+// the requested length, allows equality lookups on encrypted columns through a
+// plain expression index. The HMAC key stays in the backend; SQL never sees it.
+// pii_blind_index() is not executable by PUBLIC: whoever can compute blind
+// indexes can look up guessed values (emails, phone numbers) in stored ones.
+use crate::keys::{self, KeyKind};
+use hmac::{Hmac, Mac};
+use pgrx::prelude::*;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn normalize(value: &str, rules: &str) -> String {
+    let mut normalized = value.to_string();
+    for rule in rules.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        normalized = match rule {
+            "lower" => normalized.to_lowercase(),
+            "trim" => normalized.trim().to_string(),
+            "none" => normalized,
+            _ => pgrx::error!(
+                "pii_blind_index: unknown normalization \"{}\", expected lower, trim or none",
+                rule
+            ),
+        };
+    }
+    normalized
+}
+
+// Blind index of value under the HMAC key of index_key_name, truncated to bits
+#[pg_extern(immutable, strict, parallel_safe)]
+fn pii_blind_index(
+    value: &str,
+    index_key_name: &str,
+    normalization: default!(&str, "'lower,trim'"),
+    bits: default!(i32, 128),
+) -> Vec<u8> {
+    if !(8..=256).contains(&bits) || bits % 8 != 0 {
+        pgrx::error!("pii_blind_index: bits must be a multiple of 8 between 8 and 256");
+    }
+
+    let key = keys::resolve(KeyKind::BlindIndex, index_key_name.as_bytes()).unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let mut mac = HmacSha256::new_from_slice(&key[..]).expect("HMAC accepts any key length");
+    mac.update(normalize(value, normalization).as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    digest[..(bits / 8) as usize].to_vec()
+}
+
+extension_sql!(
+    r#"
+REVOKE EXECUTE ON FUNCTION pii_blind_index(text, text, text, integer) FROM PUBLIC;
+"#,
+    name = "pii_blind_index_revoke",
+    requires = [pii_blind_index]
+);
@@ -1,34 +1,446 @@
+// Key cache. With pg_pii_vault preloaded, keys live in the shared-memory table
+// of shared_cache and serve all backends; otherwise, and for key_ids too long
+// for it, each backend keeps its own cache.
+//
+// The backend cache holds at most pii_vault.cache_max_entries keys and picks
+// victims with the CLOCK algorithm: a hit sets the entry's reference bit, and
+// the hand clears bits until it finds an entry without one (or an expired
+// entry), which approximates LRU at O(1) cost per lookup. Keys are held in
+// protected memory (see secure.rs) and handed out by reference; the memory is
+// zeroized when the last reference to an evicted key goes.
+//
+// Cached keys belong to the Vault provider (pii_vault.url, pii_vault.mount and
+// pii_vault.token) they were exported from. The backend cache only holds keys
+// of the provider the session uses and is cleared when any of them changes;
+// the shared cache stores the provider with every key. Both are checked at
+// lookup time rather than in GUC assign hooks, which also covers SET LOCAL,
+// RESET and aborted transactions restoring an earlier value.
+//
+// Flushing or evicting a key advances the shared cache generation; backends
+// clear their own cache when they notice, before the next lookup.
+use crate::keys::{self, KeyKind};
+use crate::privileges;
+use crate::secure::SecureKey;
+use crate::stats::{self, Counter, Eviction};
+use crate::vault::{self, Provider};
+use crate::{shared_cache, PII_VAULT_CACHE_MAX_ENTRIES};
 use once_cell::sync::Lazy;
+use pgrx::prelude::*;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+type CacheKey = (KeyKind, Vec<u8>);
+
 struct CacheEntry {
-    key: [u8; 32],
+    id: CacheKey,
+    key: Arc<SecureKey>,
+    fetched_at: Instant,
     expires_at: Instant,
+    referenced: bool,
+}
+
+#[derive(Default)]
+struct LocalCache {
+    index: HashMap<CacheKey, usize>,
+    entries: Vec<CacheEntry>,
+    hand: usize,
+    // Shared cache generation and provider the entries are valid for
+    generation: Option<u64>,
+    provider: Option<Provider>,
 }
 
-static KEY_CACHE: Lazy<RwLock<HashMap<Vec<u8>, CacheEntry>>> =
-    Lazy::new(|| RwLock::new(HashMap::new()));
+impl LocalCache {
+    fn get(&mut self, id: &CacheKey) -> Option<Arc<SecureKey>> {
+        let entry = &mut self.entries[*self.index.get(id)?];
+        if entry.expires_at <= Instant::now() {
+            return None;
+        }
+        entry.referenced = true;
+        Some(entry.key.clone())
+    }
+
+    // Returns the reasons of the evictions it made room with
+    fn insert(
+        &mut self,
+        id: CacheKey,
+        key: Arc<SecureKey>,
+        ttl: Duration,
+        max: usize,
+    ) -> Vec<Eviction> {
+        let mut evicted = self.shrink_to(max);
+        let now = Instant::now();
+        let entry = CacheEntry {
+            id: id.clone(),
+            key,
+            fetched_at: now,
+            expires_at: now + ttl,
+            referenced: false,
+        };
 
-pub fn get_cached_key(key_id: &[u8]) -> Option<[u8; 32]> {
-    let cache = KEY_CACHE.read().ok()?;
-    if let Some(entry) = cache.get(key_id) {
-        if entry.expires_at > Instant::now() {
-            return Some(entry.key);
+        if let Some(&i) = self.index.get(&id) {
+            self.entries[i] = entry;
+            evicted.push(Eviction::Expired);
+        } else if self.entries.len() < max {
+            self.index.insert(id, self.entries.len());
+            self.entries.push(entry);
+        } else if max > 0 {
+            let i = self.victim();
+            let reason = match self.entries[i].expires_at <= Instant::now() {
+                true => Eviction::Expired,
+                false => Eviction::Capacity,
+            };
+            self.index.remove(&self.entries[i].id);
+            self.index.insert(id, i);
+            self.entries[i] = entry;
+            evicted.push(reason);
         }
+        evicted
     }
-    None
+
+    // Advance the hand to the next entry that is expired or was not used since
+    // the hand last passed it
+    fn victim(&mut self) -> usize {
+        let now = Instant::now();
+        loop {
+            self.hand %= self.entries.len();
+            let entry = &mut self.entries[self.hand];
+            if entry.expires_at <= now || !entry.referenced {
+                return self.hand;
+            }
+            entry.referenced = false;
+            self.hand += 1;
+        }
+    }
+
+    fn remove(&mut self, id: &CacheKey) -> bool {
+        let Some(i) = self.index.remove(id) else {
+            return false;
+        };
+        self.entries.swap_remove(i);
+        if let Some(moved) = self.entries.get(i) {
+            self.index.insert(moved.id.clone(), i);
+        }
+        true
+    }
+
+    // After pii_vault.cache_max_entries was lowered
+    fn shrink_to(&mut self, max: usize) -> Vec<Eviction> {
+        let mut evicted = Vec::new();
+        while self.entries.len() > max {
+            let id = self.entries[self.entries.len() - 1].id.clone();
+            self.remove(&id);
+            evicted.push(Eviction::Capacity);
+        }
+        evicted
+    }
+
+    fn clear(&mut self) -> usize {
+        let entries = self.entries.len();
+        self.index.clear();
+        self.entries.clear();
+        self.hand = 0;
+        entries
+    }
+}
+
+static KEY_CACHE: Lazy<Mutex<LocalCache>> = Lazy::new(|| Mutex::new(LocalCache::default()));
+
+// The backend cache, emptied first if another backend flushed or evicted keys
+// or the session switched to another provider
+fn local() -> std::sync::MutexGuard<'static, LocalCache> {
+    let mut cache = KEY_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let generation = shared_cache::generation();
+    let provider = vault::provider();
+    if cache.generation != generation || cache.provider != provider {
+        for _ in 0..cache.clear() {
+            stats::count_eviction(Eviction::Flushed);
+        }
+        cache.generation = generation;
+        cache.provider = provider;
+    }
+    cache
+}
+
+fn max_entries() -> usize {
+    PII_VAULT_CACHE_MAX_ENTRIES.get().max(0) as usize
+}
+
+pub fn get_cached_key(kind: KeyKind, key_id: &[u8]) -> Option<Arc<SecureKey>> {
+    let found = match (shared_cache::covers(key_id), vault::provider()) {
+        (_, None) => None,
+        (true, Some(provider)) => shared_cache::get(&provider, kind, key_id),
+        (false, Some(_)) => local().get(&(kind, key_id.to_vec())),
+    };
+    match found {
+        Some(_) => stats::count(Counter::CacheHit),
+        None => stats::count(Counter::CacheMiss),
+    }
+    found
+}
+
+pub fn insert_into_cache(kind: KeyKind, key_id: Vec<u8>, key: Arc<SecureKey>, ttl_secs: u64) {
+    let Some(provider) = vault::provider() else {
+        return;
+    };
+    if shared_cache::covers(&key_id) {
+        shared_cache::insert(&provider, kind, &key_id, &key, ttl_secs);
+        return;
+    }
+    let evicted = local().insert(
+        (kind, key_id),
+        key,
+        Duration::from_secs(ttl_secs),
+        max_entries(),
+    );
+    for reason in evicted {
+        stats::count_eviction(reason);
+    }
+}
+
+// Like get_cached_key(), without counting a hit or miss
+pub fn is_cached(kind: KeyKind, key_id: &[u8]) -> bool {
+    match (shared_cache::covers(key_id), vault::provider()) {
+        (_, None) => false,
+        (true, Some(provider)) => shared_cache::get(&provider, kind, key_id).is_some(),
+        (false, Some(_)) => local().get(&(kind, key_id.to_vec())).is_some(),
+    }
+}
+
+// Drop a key everywhere, for every provider; returns whether this backend or
+// the shared cache had it
+pub fn evict(kind: KeyKind, key_id: &[u8], reason: Eviction) -> bool {
+    let evicted = match shared_cache::covers(key_id) {
+        true => shared_cache::evict(kind, key_id, reason),
+        false => local().remove(&(kind, key_id.to_vec())),
+    };
+    if evicted && !shared_cache::covers(key_id) {
+        stats::count_eviction(reason);
+    }
+    shared_cache::invalidate_backends();
+    evicted
+}
+
+// Cache a dummy data key for key_id under the session's provider, and whether
+// a lookup finds it, for tests of the cache in mock mode, whose keys are never
+// cached
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_extern(volatile, strict)]
+fn pii_vault_cache_test_insert(key_id: &[u8]) {
+    let key = SecureKey::from_slice(&[0u8; 32]).expect("32-byte key");
+    insert_into_cache(KeyKind::Data, key_id.to_vec(), Arc::new(key), 300);
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_extern(volatile, strict)]
+fn pii_vault_cache_test_contains(key_id: &[u8]) -> bool {
+    is_cached(KeyKind::Data, key_id)
 }
 
-pub fn insert_into_cache(key_id: Vec<u8>, key: [u8; 32], ttl_secs: u64) {
-    if let Ok(mut cache) = KEY_CACHE.write() {
-        cache.insert(
-            key_id,
-            CacheEntry {
-                key,
-                expires_at: Instant::now() + Duration::from_secs(ttl_secs),
-            },
+// pii_vault_prefetch() against a stubbed Vault that has every key, for tests of
+// prefetching in mock mode, which fetches nothing
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_extern(volatile, strict)]
+fn pii_vault_prefetch_test(key_ids: Array<&[u8]>) -> i64 {
+    let key_ids: Vec<Vec<u8>> = key_ids.iter().flatten().map(<[u8]>::to_vec).collect();
+    let (fetched, _) = keys::prefetch_with(KeyKind::Data, &key_ids, |names| {
+        Ok(names
+            .iter()
+            .map(|_| Ok(SecureKey::from_slice(&[0u8; 32])))
+            .collect())
+    })
+    .unwrap_or_else(|e| pgrx::error!("Vault error: {}", e));
+    fetched as i64
+}
+
+// Warm the cache with the data keys of key_ids before a set-based query, so it
+// does not wait for Vault row by row; returns how many keys were fetched
+#[pg_extern(volatile, strict)]
+fn pii_vault_prefetch(key_ids: Array<&[u8]>) -> i64 {
+    let key_ids: Vec<Vec<u8>> = key_ids.iter().flatten().map(<[u8]>::to_vec).collect();
+    let (fetched, errors) = keys::prefetch(KeyKind::Data, &key_ids)
+        .unwrap_or_else(|e| pgrx::error!("Vault error: {}", e));
+    if let Some(first) = errors.first() {
+        pgrx::warning!(
+            "pii_vault: {} keys could not be prefetched: {}",
+            errors.len(),
+            first
         );
     }
+    fetched as i64
+}
+
+// Drop every cached key of the cluster (of this backend without the preloaded
+// library), e.g. after revoking keys in Vault; returns how many were dropped
+#[pg_extern(volatile)]
+fn pii_vault_cache_flush() -> i64 {
+    privileges::require_admin("pii_vault_cache_flush");
+    let mut flushed = local().clear();
+    for _ in 0..flushed {
+        stats::count_eviction(Eviction::Flushed);
+    }
+    if shared_cache::generation().is_some() {
+        flushed += shared_cache::flush();
+    }
+    shared_cache::invalidate_backends();
+    flushed as i64
+}
+
+// Drop the cached keys of key_id (data key, and namespace keys of that name)
+#[pg_extern(volatile, strict)]
+fn pii_vault_cache_evict(key_id: &[u8]) -> bool {
+    privileges::require_admin("pii_vault_cache_evict");
+    let mut evicted = false;
+    for kind in KeyKind::ALL {
+        evicted |= evict(kind, key_id, Eviction::Flushed);
+    }
+    evicted
+}
+
+// Cache occupancy and effectiveness; never key material
+#[allow(clippy::type_complexity)]
+#[pg_extern(volatile)]
+fn pii_vault_cache_info() -> TableIterator<
+    'static,
+    (
+        name!(shared_entries, Option<i64>),
+        name!(backend_entries, i64),
+        name!(max_entries, i64),
+        name!(oldest_entry_age_sec, Option<f64>),
+        name!(hits, i64),
+        name!(misses, i64),
+        name!(hit_ratio, Option<f64>),
+        name!(generation, Option<i64>),
+    ),
+> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    let (shared_entries, shared_oldest) = match shared_cache::generation() {
+        Some(_) => {
+            let (entries, oldest) = shared_cache::info();
+            (
+                Some(entries as i64),
+                oldest.map(|ms| (now_ms - ms) as f64 / 1000.0),
+            )
+        }
+        None => (None, None),
+    };
+
+    let cache = local();
+    let backend_oldest = cache
+        .entries
+        .iter()
+        .map(|e| e.fetched_at.elapsed().as_secs_f64())
+        .reduce(f64::max);
+    let backend_entries = cache.entries.len() as i64;
+    drop(cache);
+
+    let oldest = match (shared_oldest, backend_oldest) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    };
+    let hits = stats::counter(Counter::CacheHit);
+    let misses = stats::counter(Counter::CacheMiss);
+    let hit_ratio = (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64);
+
+    TableIterator::once((
+        shared_entries,
+        backend_entries,
+        max_entries() as i64,
+        oldest,
+        hits,
+        misses,
+        hit_ratio,
+        shared_cache::generation().map(|g| g as i64),
+    ))
+}
+
+extension_sql!(
+    r#"
+REVOKE EXECUTE ON FUNCTION pii_vault_cache_flush() FROM PUBLIC;
+REVOKE EXECUTE ON FUNCTION pii_vault_cache_evict(bytea) FROM PUBLIC;
+"#,
+    name = "pii_vault_cache_revoke",
+    requires = [pii_vault_cache_flush, pii_vault_cache_evict]
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn id(n: u8) -> CacheKey {
+        (KeyKind::Data, vec![n])
+    }
+
+    fn key() -> Arc<SecureKey> {
+        Arc::new(SecureKey::from_slice(&[7u8; 32]).expect("32-byte key"))
+    }
+
+    fn assert_consistent(cache: &LocalCache) {
+        assert_eq!(cache.index.len(), cache.entries.len());
+        for (id, &i) in &cache.index {
+            assert_eq!(&cache.entries[i].id, id);
+        }
+    }
+
+    #[test]
+    fn referenced_entry_survives_one_sweep() {
+        let mut cache = LocalCache::default();
+        for n in 0..3 {
+            assert!(cache.insert(id(n), key(), TTL, 3).is_empty());
+        }
+        assert!(cache.get(&id(0)).is_some());
+
+        // The hand clears the bit of 0 and takes 1, the first unreferenced entry
+        let evicted = cache.insert(id(3), key(), TTL, 3);
+        assert!(matches!(evicted[..], [Eviction::Capacity]));
+        assert!(cache.get(&id(1)).is_none());
+        for n in [0, 2, 3] {
+            assert!(cache.get(&id(n)).is_some());
+        }
+        assert_consistent(&cache);
+    }
+
+    #[test]
+    fn size_is_capped() {
+        let mut cache = LocalCache::default();
+        let mut evicted = 0;
+        for n in 0..10 {
+            evicted += cache.insert(id(n), key(), TTL, 4).len();
+        }
+        assert_eq!(cache.entries.len(), 4);
+        assert_eq!(evicted, 6);
+        assert!(cache.get(&id(9)).is_some());
+        assert_consistent(&cache);
+
+        // Nothing is cached with a limit of 0
+        let mut cache = LocalCache::default();
+        assert!(cache.insert(id(0), key(), TTL, 0).is_empty());
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn shrinks_when_limit_is_lowered() {
+        let mut cache = LocalCache::default();
+        for n in 0..5 {
+            cache.insert(id(n), key(), TTL, 5);
+        }
+
+        // The next insert under a lower pii_vault.cache_max_entries first
+        // shrinks the cache, then makes room for the new key
+        let evicted = cache.insert(id(5), key(), TTL, 2);
+        assert_eq!(evicted.len(), 4);
+        assert!(evicted.iter().all(|e| matches!(e, Eviction::Capacity)));
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get(&id(5)).is_some());
+        assert_consistent(&cache);
+
+        assert_eq!(cache.shrink_to(1).len(), 1);
+        assert_eq!(cache.entries.len(), 1);
+        assert_consistent(&cache);
+    }
 }
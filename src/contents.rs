@@ -11,8 +11,47 @@ pub struct PiiSealedData {
     pub iv: Vec<u8>,
     #[serde(rename = "t")]
     pub tag: Vec<u8>,
-    #[serde(rename = "c")]
+    // Encoded as a CBOR byte string; older envelopes stored an integer array,
+    // which serde_bytes still accepts on read
+    #[serde(rename = "c", with = "serde_bytes")]
     pub ciphertext: Vec<u8>,
+    // Cipher, see crypto::ALG_*; absent means AES-256-GCM
+    #[serde(rename = "a", default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<u8>,
+    // Optional AAD bindings to a column policy (version 2 envelopes)
+    #[serde(rename = "r", default, skip_serializing_if = "Option::is_none")]
+    pub relation: Option<String>,
+    #[serde(rename = "f", default, skip_serializing_if = "Option::is_none")]
+    pub attribute: Option<String>,
+}
+
+impl PiiSealedData {
+    pub fn aad(&self, type_name: &str) -> String {
+        aad(
+            type_name,
+            &self.key_id,
+            self.relation.as_deref(),
+            self.attribute.as_deref(),
+        )
+    }
+}
+
+// AAD: col:<type>:id:<hex key_id>[:rel:<hex label>][:att:<hex label>]
+// Labels are hex encoded so they cannot smuggle in separators.
+pub fn aad(
+    type_name: &str,
+    key_id: &[u8],
+    relation: Option<&str>,
+    attribute: Option<&str>,
+) -> String {
+    let mut context = format!("col:{}:id:{}", type_name, hex::encode(key_id));
+    if let Some(r) = relation {
+        context.push_str(&format!(":rel:{}", hex::encode(r)));
+    }
+    if let Some(a) = attribute {
+        context.push_str(&format!(":att:{}", hex::encode(a)));
+    }
+    context
 }
 
 #[derive(Debug)]
@@ -21,6 +60,12 @@ pub enum PiiTextContents<'a> {
     Sealed(PiiSealedData),
 }
 
+#[derive(Debug)]
+pub enum PiiByteaContents<'a> {
+    Staging(Cow<'a, [u8]>),
+    Sealed(PiiSealedData),
+}
+
 // Implement From/TryFrom for type conversions
 impl<'a> From<&'a [u8]> for PiiTextContents<'a> {
     fn from(bytes: &'a [u8]) -> Self {
@@ -53,3 +98,24 @@ impl<'a> From<&PiiTextContents<'a>> for Vec<u8> {
         }
     }
 }
+
+impl<'a> From<&'a [u8]> for PiiByteaContents<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        if let Ok(sealed) = serde_cbor::from_slice(bytes) {
+            PiiByteaContents::Sealed(sealed)
+        } else {
+            PiiByteaContents::Staging(Cow::Borrowed(bytes))
+        }
+    }
+}
+
+impl<'a> From<PiiByteaContents<'a>> for Vec<u8> {
+    fn from(contents: PiiByteaContents<'a>) -> Vec<u8> {
+        match contents {
+            PiiByteaContents::Staging(b) => b.into_owned(),
+            PiiByteaContents::Sealed(data) => {
+                serde_cbor::to_vec(&data).expect("CBOR serialization failed")
+            }
+        }
+    }
+}
@@ -1,8 +1,16 @@
 use crate::contents::PiiSealedData;
+use crate::stats::{self, Counter, Failure};
 use aes_gcm::{
     aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
+use aes_gcm_siv::Aes256GcmSiv;
+
+// Randomized AES-256-GCM with a fresh IV per value
+pub const ALG_AES_256_GCM: u8 = 1;
+// Deterministic AES-256-GCM-SIV with a fixed IV: equal plaintexts under the same
+// key and AAD give equal ciphertexts, which is what makes = and joins work
+pub const ALG_AES_256_GCM_SIV: u8 = 2;
 
 pub fn encrypt(
     plaintext: &str,
@@ -10,6 +18,16 @@ pub fn encrypt(
     key_id: &[u8],
     context: &str,
 ) -> Result<PiiSealedData, String> {
+    encrypt_bytes(plaintext.as_bytes(), key, key_id, context)
+}
+
+pub fn encrypt_bytes(
+    plaintext: &[u8],
+    key: &[u8; 32],
+    key_id: &[u8],
+    context: &str,
+) -> Result<PiiSealedData, String> {
+    stats::count(Counter::Encrypt);
     let cipher = Aes256Gcm::new(key.into());
     let mut iv_bytes = [0u8; 12];
     unsafe {
@@ -20,7 +38,7 @@ pub fn encrypt(
 
     let nonce = Nonce::from_slice(&iv_bytes);
     let payload = Payload {
-        msg: plaintext.as_bytes(),
+        msg: plaintext,
         aad: context.as_bytes(),
     };
 
@@ -40,24 +58,101 @@ pub fn encrypt(
         iv: iv_bytes.to_vec(),
         tag,
         ciphertext,
+        algorithm: None,
+        relation: None,
+        attribute: None,
     })
 }
 
-pub fn decrypt(data: &PiiSealedData, key: &[u8; 32], context: &str) -> Result<String, String> {
-    let cipher = Aes256Gcm::new(key.into());
-    let nonce = Nonce::from_slice(&data.iv);
-
-    let mut ciphertext_with_tag = data.ciphertext.clone();
-    ciphertext_with_tag.extend_from_slice(&data.tag);
+pub fn encrypt_deterministic(
+    plaintext: &[u8],
+    key: &[u8; 32],
+    key_id: &[u8],
+    context: &str,
+) -> Result<PiiSealedData, String> {
+    stats::count(Counter::Encrypt);
+    let cipher = Aes256GcmSiv::new(key.into());
+    let iv_bytes = [0u8; 12];
 
     let payload = Payload {
-        msg: &ciphertext_with_tag,
+        msg: plaintext,
         aad: context.as_bytes(),
     };
 
-    let plaintext_bytes = cipher
-        .decrypt(nonce, payload)
-        .map_err(|e| format!("Decryption failed: {}", e))?;
+    let ciphertext_with_tag = cipher
+        .encrypt(aes_gcm_siv::Nonce::from_slice(&iv_bytes), payload)
+        .map_err(|e| format!("Encryption failed: {}", e))?;
+
+    let tag_pos = ciphertext_with_tag.len() - 16;
+    Ok(PiiSealedData {
+        version: 1,
+        key_id: key_id.to_vec(),
+        iv: iv_bytes.to_vec(),
+        tag: ciphertext_with_tag[tag_pos..].to_vec(),
+        ciphertext: ciphertext_with_tag[..tag_pos].to_vec(),
+        algorithm: Some(ALG_AES_256_GCM_SIV),
+        relation: None,
+        attribute: None,
+    })
+}
+
+pub fn decrypt(data: &PiiSealedData, key: &[u8; 32], context: &str) -> Result<String, String> {
+    let plaintext_bytes = decrypt_bytes(data, key, context)?;
 
     String::from_utf8(plaintext_bytes).map_err(|e| format!("Invalid UTF-8: {}", e))
 }
+
+pub fn decrypt_bytes(
+    data: &PiiSealedData,
+    key: &[u8; 32],
+    context: &str,
+) -> Result<Vec<u8>, String> {
+    Cipher::new(data.algorithm, key)?.decrypt_bytes(data, context)
+}
+
+// The cipher of an algorithm with its key schedule set up, for decrypting many
+// values under the same key without setting it up again
+pub enum Cipher {
+    Gcm(Aes256Gcm),
+    GcmSiv(Aes256GcmSiv),
+}
+
+impl Cipher {
+    pub fn new(algorithm: Option<u8>, key: &[u8; 32]) -> Result<Cipher, String> {
+        match algorithm.unwrap_or(ALG_AES_256_GCM) {
+            ALG_AES_256_GCM => Ok(Cipher::Gcm(Aes256Gcm::new(key.into()))),
+            ALG_AES_256_GCM_SIV => Ok(Cipher::GcmSiv(Aes256GcmSiv::new(key.into()))),
+            other => {
+                stats::count_failure(Failure::Malformed);
+                Err(format!("Unknown algorithm: {}", other))
+            }
+        }
+    }
+
+    pub fn decrypt(&self, data: &PiiSealedData, context: &str) -> Result<String, String> {
+        let plaintext_bytes = self.decrypt_bytes(data, context)?;
+
+        String::from_utf8(plaintext_bytes).map_err(|e| format!("Invalid UTF-8: {}", e))
+    }
+
+    pub fn decrypt_bytes(&self, data: &PiiSealedData, context: &str) -> Result<Vec<u8>, String> {
+        let mut ciphertext_with_tag = data.ciphertext.clone();
+        ciphertext_with_tag.extend_from_slice(&data.tag);
+
+        let payload = Payload {
+            msg: &ciphertext_with_tag,
+            aad: context.as_bytes(),
+        };
+
+        match self {
+            Cipher::Gcm(cipher) => cipher.decrypt(Nonce::from_slice(&data.iv), payload),
+            Cipher::GcmSiv(cipher) => {
+                cipher.decrypt(aes_gcm_siv::Nonce::from_slice(&data.iv), payload)
+            }
+        }
+        .map_err(|e| {
+            stats::count_failure(Failure::Authentication);
+            format!("Decryption failed: {}", e)
+        })
+    }
+}
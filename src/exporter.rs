@@ -0,0 +1,253 @@
+// Prometheus exporter: with pii_vault.metrics_port set and pg_pii_vault in
+// shared_preload_libraries, a background worker serves the statistics of
+// pg_stat_pii_vault in the Prometheus text exposition format at
+// http://<pii_vault.metrics_listen_address>:<port>/metrics. The worker has no
+// database connection; it only reads the shared-memory counters.
+use crate::{stats, PII_VAULT_METRICS_LISTEN_ADDRESS, PII_VAULT_METRICS_PORT};
+use pgrx::bgworkers::*;
+use pgrx::prelude::*;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+// pg_stat_pii_vault rows and the metric each becomes; vault_latency_* rows make
+// up the request duration histogram
+const METRICS: [(&str, &str, &str, &str); 14] = [
+    (
+        "encrypt_calls",
+        "pii_vault_encrypt_calls_total",
+        "counter",
+        "Values sealed",
+    ),
+    (
+        "decrypt_calls",
+        "pii_vault_decrypt_calls_total",
+        "counter",
+        "Decryptions attempted",
+    ),
+    (
+        "cache_hits",
+        "pii_vault_cache_hits_total",
+        "counter",
+        "Key cache hits",
+    ),
+    (
+        "cache_misses",
+        "pii_vault_cache_misses_total",
+        "counter",
+        "Key cache misses",
+    ),
+    (
+        "cache_evictions",
+        "pii_vault_cache_evictions_total",
+        "counter",
+        "Key cache entries dropped",
+    ),
+    (
+        "keys_created",
+        "pii_vault_keys_created_total",
+        "counter",
+        "Keys created in Vault on first use",
+    ),
+    (
+        "cache_refreshes",
+        "pii_vault_cache_refreshes_total",
+        "counter",
+        "Hot keys refreshed by the key cache worker",
+    ),
+    (
+        "decrypt_failures",
+        "pii_vault_decrypt_failures_total",
+        "counter",
+        "Failed decryptions by cause",
+    ),
+    (
+        "vault_requests",
+        "pii_vault_vault_requests_total",
+        "counter",
+        "Vault requests by endpoint and status class",
+    ),
+    (
+        "vault_consecutive_failures",
+        "pii_vault_vault_consecutive_failures",
+        "gauge",
+        "Failed Vault requests since the last answer",
+    ),
+    (
+        "vault_last_success",
+        "pii_vault_vault_last_success_timestamp_seconds",
+        "gauge",
+        "Unix time of the last answered Vault request",
+    ),
+    (
+        "vault_last_failure",
+        "pii_vault_vault_last_failure_timestamp_seconds",
+        "gauge",
+        "Unix time of the last failed Vault request",
+    ),
+    (
+        "stats_reset",
+        "pii_vault_stats_reset_timestamp_seconds",
+        "gauge",
+        "Unix time of the last statistics reset",
+    ),
+    (
+        "shared_memory",
+        "pii_vault_stats_shared",
+        "gauge",
+        "1 when the statistics cover the whole cluster",
+    ),
+];
+
+const DURATION: &str = "pii_vault_vault_request_duration_seconds";
+
+// "endpoint=export,le=5" as {endpoint="export",le="0.005"}; le is in
+// milliseconds in the view and in seconds for Prometheus
+fn labels(label: &str) -> String {
+    if label.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = label
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| match (key, value.parse::<u64>()) {
+            ("le", Ok(ms)) => format!("le=\"{}\"", ms as f64 / 1000.0),
+            _ => format!("{}=\"{}\"", key, value),
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+pub fn exposition() -> String {
+    let rows = stats::rows();
+    let mut out = String::new();
+
+    for (row, metric, kind, help) in METRICS {
+        out.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            metric, help, metric, kind
+        ));
+        for (_, label, value) in rows.iter().filter(|(name, _, _)| name == row) {
+            out.push_str(&format!("{}{} {}\n", metric, labels(label), value));
+        }
+    }
+
+    out.push_str(&format!(
+        "# HELP {} Duration of Vault requests by endpoint\n# TYPE {} histogram\n",
+        DURATION, DURATION
+    ));
+    for (name, label, value) in &rows {
+        match name.as_str() {
+            "vault_latency_ms_bucket" => {
+                out.push_str(&format!("{}_bucket{} {}\n", DURATION, labels(label), value));
+                if label.ends_with("le=+Inf") {
+                    let endpoint = label.trim_end_matches(",le=+Inf");
+                    out.push_str(&format!(
+                        "{}_count{} {}\n",
+                        DURATION,
+                        labels(endpoint),
+                        value
+                    ));
+                }
+            }
+            "vault_latency_us_sum" => {
+                out.push_str(&format!(
+                    "{}_sum{} {}\n",
+                    DURATION,
+                    labels(label),
+                    *value as f64 / 1_000_000.0
+                ));
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+// The metrics page pg_pii_vault's exporter serves, for checking it from SQL
+#[pg_extern(volatile)]
+fn pii_vault_metrics() -> String {
+    exposition()
+}
+
+// Called from _PG_init after the GUCs are defined
+pub fn init() {
+    let preloading = unsafe { pg_sys::process_shared_preload_libraries_in_progress };
+    if !preloading || PII_VAULT_METRICS_PORT.get() == 0 {
+        return;
+    }
+
+    BackgroundWorkerBuilder::new("pii_vault metrics exporter")
+        .set_type("pii_vault metrics exporter")
+        .set_library("pg_pii_vault")
+        .set_function("pii_vault_metrics_main")
+        .enable_shmem_access(None)
+        .set_start_time(BgWorkerStartTime::ConsistentState)
+        .set_restart_time(Some(Duration::from_secs(10)))
+        .load();
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn pii_vault_metrics_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    let host = PII_VAULT_METRICS_LISTEN_ADDRESS
+        .get()
+        .map(|a| a.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "127.0.0.1".to_string());
+    let address = format!("{}:{}", host, PII_VAULT_METRICS_PORT.get());
+
+    // An error exits the worker, which the postmaster restarts after 10 seconds
+    let listener = TcpListener::bind(&address).unwrap_or_else(|e| {
+        pgrx::error!(
+            "pii_vault: metrics exporter cannot listen on {}: {}",
+            address,
+            e
+        )
+    });
+    listener
+        .set_nonblocking(true)
+        .unwrap_or_else(|e| pgrx::error!("pii_vault: metrics exporter: {}", e));
+    pgrx::log!("pii_vault: metrics exporter listening on {}", address);
+
+    while BackgroundWorker::wait_latch(Some(Duration::from_millis(100))) {
+        while let Ok((stream, _)) = listener.accept() {
+            if let Err(e) = serve(stream) {
+                pgrx::debug1!("pii_vault: metrics request failed: {}", e);
+            }
+        }
+    }
+}
+
+// One HTTP/1.x request per connection; anything but GET /metrics is a 404
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", exposition()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,119 @@
+// Format-preserving encryption (FF1, NIST SP 800-38G) for identifiers that
+// downstream systems validate by shape: phone numbers, account numbers, codes.
+// Characters of the alphabet are encrypted as one numeral string, everything
+// else (separators, "+", spaces) stays where it is, so the result has the same
+// length and character classes as the input. FF3-1 is not offered: the fpe
+// crate only implements FF1, which has no tweak length restrictions.
+//
+// The FF1 key is derived from the subject's data key, so shredding the subject
+// also makes its FPE values undecryptable; the tweak is the key_id itself.
+// Decrypting never creates the data key: a shredded subject raises an error.
+use crate::keys::{self, KeyKind};
+use crate::{audit, privileges};
+use aes::Aes256;
+use fpe::ff1::{FlexibleNumeralString, FF1};
+use hmac::{Hmac, Mac};
+use pgrx::prelude::*;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DIGITS: &str = "0123456789";
+const LOWER: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPER: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+// Preset name or a literal alphabet of at least two distinct characters
+fn alphabet(spec: &str) -> Vec<char> {
+    let chars: Vec<char> = match spec {
+        "digits" => DIGITS.chars().collect(),
+        "hex" => "0123456789abcdef".chars().collect(),
+        "lower" => LOWER.chars().collect(),
+        "upper" => UPPER.chars().collect(),
+        "alpha" => LOWER.chars().chain(UPPER.chars()).collect(),
+        "alphanumeric" => DIGITS
+            .chars()
+            .chain(LOWER.chars())
+            .chain(UPPER.chars())
+            .collect(),
+        custom => custom.chars().collect(),
+    };
+
+    let mut distinct = chars.clone();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() != chars.len() || chars.len() < 2 || chars.len() > u16::MAX as usize {
+        pgrx::error!(
+            "pii_fpe: alphabet must be digits, hex, lower, upper, alpha, alphanumeric or at least two distinct characters"
+        );
+    }
+    chars
+}
+
+fn ff1_key(key_id: &[u8], decrypt: bool) -> Zeroizing<[u8; 32]> {
+    let data_key = match decrypt {
+        true => keys::resolve_existing(KeyKind::Data, key_id).unwrap_or_else(|e| {
+            audit::record_key(key_id, None, None, audit::Outcome::Failed);
+            pgrx::error!("pii_fpe: {}", e)
+        }),
+        false => keys::resolve_key(key_id).unwrap_or_else(|e| {
+            pgrx::error!("Vault error: {}", e);
+        }),
+    };
+
+    let mut mac = HmacSha256::new_from_slice(&data_key[..]).expect("HMAC accepts any key length");
+    mac.update(b"pii_vault:ff1");
+    Zeroizing::new(mac.finalize().into_bytes().into())
+}
+
+fn transform(value: &str, key_id: &[u8], alphabet_spec: &str, decrypt: bool) -> String {
+    let alphabet = alphabet(alphabet_spec);
+    let chars: Vec<char> = value.chars().collect();
+    let numerals: Vec<u16> = chars
+        .iter()
+        .filter_map(|c| alphabet.iter().position(|a| a == c).map(|p| p as u16))
+        .collect();
+
+    let ff1 = FF1::<Aes256>::new(&ff1_key(key_id, decrypt)[..], alphabet.len() as u32)
+        .unwrap_or_else(|e| pgrx::error!("pii_fpe: {}", e));
+    let input = FlexibleNumeralString::from(numerals);
+    let output = if decrypt {
+        ff1.decrypt(key_id, &input)
+    } else {
+        ff1.encrypt(key_id, &input)
+    }
+    .unwrap_or_else(|e| pgrx::error!("pii_fpe: {}", e));
+
+    let mut output = Vec::<u16>::from(output).into_iter();
+    chars
+        .into_iter()
+        .map(|c| match alphabet.contains(&c) {
+            true => alphabet[output.next().expect("FF1 preserves length") as usize],
+            false => c,
+        })
+        .collect()
+}
+
+// Encrypt the characters of value that belong to alphabet, keeping its format
+#[pg_extern(immutable, strict)]
+fn pii_fpe_encrypt(
+    value: &str,
+    key_id_bytes: &[u8],
+    alphabet: default!(&str, "'digits'"),
+) -> String {
+    transform(value, key_id_bytes, alphabet, false)
+}
+
+// Inverse of pii_fpe_encrypt with the same key_id and alphabet. Stable, since
+// its result depends on the reader role and every call is audited.
+#[pg_extern(stable, strict)]
+fn pii_fpe_decrypt(
+    value: &str,
+    key_id_bytes: &[u8],
+    alphabet: default!(&str, "'digits'"),
+) -> String {
+    privileges::require_reader("pii_fpe_decrypt");
+    let plaintext = transform(value, key_id_bytes, alphabet, true);
+    audit::record_key(key_id_bytes, None, None, audit::Outcome::Plaintext);
+    plaintext
+}
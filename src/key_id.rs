@@ -0,0 +1,127 @@
+// Canonical key_id encoding for subject identifiers:
+//
+//   int2 / int4  4 bytes, big-endian two's complement
+//   int8         8 bytes, big-endian two's complement
+//   uuid         16 raw bytes (same as uuid_send)
+//   text         UTF-8 bytes, no terminator
+//   bytea        unchanged
+//
+// The encoding is untagged so ids produced by the old lpad(to_hex(id)) helpers
+// and uuid_send() keep resolving to the same Vault keys.
+use crate::{piitext_encrypt, PiiText};
+use pgrx::pg_sys::PgBuiltInOids;
+use pgrx::prelude::*;
+use pgrx::{AnyElement, PgOid};
+
+pub fn from_int4(id: i32) -> Vec<u8> {
+    id.to_be_bytes().to_vec()
+}
+
+pub fn from_int8(id: i64) -> Vec<u8> {
+    id.to_be_bytes().to_vec()
+}
+
+pub fn from_uuid(id: &pgrx::Uuid) -> Vec<u8> {
+    id.as_bytes().to_vec()
+}
+
+pub fn from_text(id: &str) -> Vec<u8> {
+    id.as_bytes().to_vec()
+}
+
+// Keys of a namespace (see pii_vault.column_policy) are prefixed "<namespace>:"
+pub fn namespaced(namespace: &str, key_id: Vec<u8>) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(namespace.len() + 1 + key_id.len());
+    prefixed.extend_from_slice(namespace.as_bytes());
+    prefixed.push(b':');
+    prefixed.extend_from_slice(&key_id);
+    prefixed
+}
+
+// Inverse of the canonical encoding. The encoding carries no type tag, so every
+// subject id the bytes could have come from is listed: int4, int8 or uuid by
+// length, text when they are printable UTF-8, and bytea when nothing else fits.
+pub fn render(key_id: &[u8]) -> String {
+    let mut readings = Vec::new();
+    if let Ok(bytes) = <[u8; 4]>::try_from(key_id) {
+        readings.push(format!("int4 {}", i32::from_be_bytes(bytes)));
+    }
+    if let Ok(bytes) = <[u8; 8]>::try_from(key_id) {
+        readings.push(format!("int8 {}", i64::from_be_bytes(bytes)));
+    }
+    if key_id.len() == 16 {
+        let h = hex::encode(key_id);
+        readings.push(format!(
+            "uuid {}-{}-{}-{}-{}",
+            &h[0..8],
+            &h[8..12],
+            &h[12..16],
+            &h[16..20],
+            &h[20..32]
+        ));
+    }
+    match std::str::from_utf8(key_id) {
+        Ok(s) if !s.chars().any(char::is_control) => readings.push(format!("text {:?}", s)),
+        _ if readings.is_empty() => readings.push(format!("bytea \\x{}", hex::encode(key_id))),
+        _ => {}
+    }
+    readings.join(" or ")
+}
+
+// The value of an anyelement argument whose type was checked by its oid
+fn value<T: FromDatum>(subject_id: &AnyElement) -> Option<T> {
+    unsafe { subject_id.into::<T>() }
+}
+
+// Derive a key_id from a subject identifier of any supported type
+#[pg_extern(immutable, strict)]
+fn pii_key_id(subject_id: AnyElement) -> Vec<u8> {
+    match PgOid::from(subject_id.oid()) {
+        PgOid::BuiltIn(PgBuiltInOids::INT2OID) => {
+            from_int4(value::<i16>(&subject_id).expect("int2 value") as i32)
+        }
+        PgOid::BuiltIn(PgBuiltInOids::INT4OID) => {
+            from_int4(value::<i32>(&subject_id).expect("int4 value"))
+        }
+        PgOid::BuiltIn(PgBuiltInOids::INT8OID) => {
+            from_int8(value::<i64>(&subject_id).expect("int8 value"))
+        }
+        PgOid::BuiltIn(PgBuiltInOids::UUIDOID) => {
+            from_uuid(&value::<pgrx::Uuid>(&subject_id).expect("uuid value"))
+        }
+        PgOid::BuiltIn(PgBuiltInOids::TEXTOID)
+        | PgOid::BuiltIn(PgBuiltInOids::VARCHAROID)
+        | PgOid::BuiltIn(PgBuiltInOids::BPCHAROID) => {
+            from_text(&value::<String>(&subject_id).expect("text value"))
+        }
+        PgOid::BuiltIn(PgBuiltInOids::BYTEAOID) => {
+            value::<Vec<u8>>(&subject_id).expect("bytea value")
+        }
+        _ => pgrx::error!(
+            "pii_key_id: unsupported subject id type (oid {}), expected int2, int4, int8, uuid, text or bytea",
+            subject_id.oid()
+        ),
+    }
+}
+
+#[pg_extern(immutable, strict, name = "piitext_encrypt")]
+fn piitext_encrypt_int4(plaintext: &str, subject_id: i32) -> PiiText {
+    piitext_encrypt(plaintext, from_int4(subject_id))
+}
+
+#[pg_extern(immutable, strict, name = "piitext_encrypt")]
+fn piitext_encrypt_int8(plaintext: &str, subject_id: i64) -> PiiText {
+    piitext_encrypt(plaintext, from_int8(subject_id))
+}
+
+#[pg_extern(immutable, strict, name = "piitext_encrypt")]
+fn piitext_encrypt_uuid(plaintext: &str, subject_id: pgrx::Uuid) -> PiiText {
+    piitext_encrypt(plaintext, from_uuid(&subject_id))
+}
+
+// Not an overload of piitext_encrypt: an untyped literal such as '\x0102' would
+// resolve to it rather than to bytea and silently derive another key_id
+#[pg_extern(immutable, strict)]
+fn piitext_encrypt_text_id(plaintext: &str, subject_id: &str) -> PiiText {
+    piitext_encrypt(plaintext, from_text(subject_id))
+}
@@ -0,0 +1,196 @@
+use crate::contents::PiiSealedData;
+use crate::secure::SecureKey;
+use crate::stats::{self, Counter, Failure};
+use crate::PII_VAULT_PREFETCH_PARALLELISM;
+use crate::{cache, crypto, shred, vault, PII_VAULT_CACHE_TTL, PII_VAULT_URL};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+// Classes of keys kept in Vault. Each class has its own Vault key names and
+// cache entries, so a blind index name can never resolve to a subject's data key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyKind {
+    // Per-subject AES-256-GCM data key, named hex(key_id)
+    Data,
+    // HMAC key of a blind index, named pii-bidx-<name>
+    BlindIndex,
+    // AES-256-GCM-SIV key shared by a deterministic namespace, named pii-det-<namespace>
+    Deterministic,
+    // HMAC key of a tokenization namespace, named pii-tok-<namespace>
+    Token,
+    // HMAC key of a pseudonymization project, named pii-psn-<project>
+    Pseudonym,
+}
+
+impl KeyKind {
+    // In discriminant order, so that ALL[kind as usize] == kind
+    pub const ALL: [KeyKind; 5] = [
+        KeyKind::Data,
+        KeyKind::BlindIndex,
+        KeyKind::Deterministic,
+        KeyKind::Token,
+        KeyKind::Pseudonym,
+    ];
+
+    pub fn vault_key_name(self, key_id: &[u8]) -> String {
+        match self {
+            KeyKind::Data => hex::encode(key_id),
+            KeyKind::BlindIndex => format!("pii-bidx-{}", String::from_utf8_lossy(key_id)),
+            KeyKind::Deterministic => format!("pii-det-{}", String::from_utf8_lossy(key_id)),
+            KeyKind::Token => format!("pii-tok-{}", String::from_utf8_lossy(key_id)),
+            KeyKind::Pseudonym => format!("pii-psn-{}", String::from_utf8_lossy(key_id)),
+        }
+    }
+
+    pub fn export_type(self) -> &'static str {
+        match self {
+            KeyKind::Data | KeyKind::Deterministic => "encryption-key",
+            KeyKind::BlindIndex | KeyKind::Token | KeyKind::Pseudonym => "hmac-key",
+        }
+    }
+}
+
+// Mock mode (pii_vault.url = 'mock://...') uses an all-zero key and never talks to Vault
+pub fn is_mock() -> bool {
+    match PII_VAULT_URL.get() {
+        Some(ref u) => u.to_str().unwrap_or("").starts_with("mock://"),
+        None => false,
+    }
+}
+
+// Resolve the data key for key_id: mock key, cached key, or exported from Vault
+pub fn resolve_key(key_id: &[u8]) -> Result<Arc<SecureKey>, String> {
+    resolve(KeyKind::Data, key_id)
+}
+
+// Resolve the key a sealed value was encrypted with; deterministic values carry
+// their namespace in key_id. Unlike resolve(), a key missing from Vault is not
+// created but reported to the shredding ledger.
+pub fn resolve_sealed(sealed: &PiiSealedData) -> Result<Arc<SecureKey>, String> {
+    stats::count(Counter::Decrypt);
+    resolve_sealed_key(sealed)
+}
+
+pub fn sealed_kind(sealed: &PiiSealedData) -> KeyKind {
+    match sealed.algorithm {
+        Some(crypto::ALG_AES_256_GCM_SIV) => KeyKind::Deterministic,
+        _ => KeyKind::Data,
+    }
+}
+
+// resolve_sealed() without counting a decryption, for callers that decrypt
+// several values with the key and count each of them
+pub fn resolve_sealed_key(sealed: &PiiSealedData) -> Result<Arc<SecureKey>, String> {
+    resolve_existing(sealed_kind(sealed), &sealed.key_id)
+}
+
+// Resolve a key that data was already encrypted with, for decrypting: a key
+// missing from Vault has been shredded and is never created again
+pub fn resolve_existing(kind: KeyKind, key_id: &[u8]) -> Result<Arc<SecureKey>, String> {
+    if is_mock() {
+        return Ok(Arc::new(SecureKey::zeroed()));
+    }
+
+    if let Some(k) = cache::get_cached_key(kind, key_id) {
+        return Ok(k);
+    }
+
+    let exported = vault::export_existing_key(&kind.vault_key_name(key_id), kind.export_type())
+        .inspect_err(|_| stats::count_failure(Failure::Vault))?;
+    match exported {
+        Some(k) => {
+            let k = Arc::new(k);
+            cache::insert_into_cache(
+                kind,
+                key_id.to_vec(),
+                k.clone(),
+                PII_VAULT_CACHE_TTL.get() as u64,
+            );
+            Ok(k)
+        }
+        None => {
+            stats::count_failure(Failure::KeyShredded);
+            shred::key_missing(kind, key_id);
+            Err("key has been shredded".to_string())
+        }
+    }
+}
+
+pub fn resolve(kind: KeyKind, key_id: &[u8]) -> Result<Arc<SecureKey>, String> {
+    if is_mock() {
+        return Ok(Arc::new(SecureKey::zeroed()));
+    }
+
+    if let Some(k) = cache::get_cached_key(kind, key_id) {
+        return Ok(k);
+    }
+
+    let k = Arc::new(vault::export_key(
+        &kind.vault_key_name(key_id),
+        kind.export_type(),
+    )?);
+    cache::insert_into_cache(
+        kind,
+        key_id.to_vec(),
+        k.clone(),
+        PII_VAULT_CACHE_TTL.get() as u64,
+    );
+    Ok(k)
+}
+
+// Fetch the existing keys of key_ids that are not cached yet into the cache,
+// pii_vault.prefetch_parallelism at a time. Keys missing from Vault are skipped;
+// decrypting reports them. Returns how many keys were fetched and the errors
+// of those that could not be.
+pub fn prefetch(kind: KeyKind, key_ids: &[Vec<u8>]) -> Result<(usize, Vec<String>), String> {
+    if is_mock() {
+        return Ok((0, Vec::new()));
+    }
+    prefetch_with(kind, key_ids, |names| {
+        vault::export_existing_keys(names, PII_VAULT_PREFETCH_PARALLELISM.get() as usize)
+    })
+}
+
+// Results of exporting named keys, in the order of the names
+pub type Exported = Vec<Result<Option<SecureKey>, String>>;
+
+// prefetch() with the Vault exports done by export, which tests stub
+pub fn prefetch_with(
+    kind: KeyKind,
+    key_ids: &[Vec<u8>],
+    export: impl FnOnce(&[(String, &str)]) -> Result<Exported, String>,
+) -> Result<(usize, Vec<String>), String> {
+    let mut seen = HashSet::new();
+    let wanted: Vec<&Vec<u8>> = key_ids
+        .iter()
+        .filter(|key_id| seen.insert(*key_id) && !cache::is_cached(kind, key_id))
+        .collect();
+    if wanted.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+
+    let names: Vec<(String, &str)> = wanted
+        .iter()
+        .map(|key_id| (kind.vault_key_name(key_id), kind.export_type()))
+        .collect();
+    let exported = export(&names)?;
+
+    let mut fetched = 0;
+    let mut errors = Vec::new();
+    for (key_id, result) in wanted.into_iter().zip(exported) {
+        match result {
+            Ok(Some(k)) => {
+                cache::insert_into_cache(
+                    kind,
+                    key_id.clone(),
+                    Arc::new(k),
+                    PII_VAULT_CACHE_TTL.get() as u64,
+                );
+                fetched += 1;
+            }
+            Ok(None) => {}
+            Err(e) => errors.push(e),
+        }
+    }
+    Ok((fetched, errors))
+}
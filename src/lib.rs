@@ -2,18 +2,55 @@ use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
 use pgrx::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::ffi::CString;
+use std::sync::Arc;
+use zeroize::Zeroizing;
 
+mod audit;
+mod blind_index;
 mod cache;
 mod contents;
 mod crypto;
+mod exporter;
+mod format_preserving;
+mod key_id;
+mod keys;
+mod mask;
+mod piibytea;
+mod policy;
+mod privileges;
+mod pseudonym;
+mod scalars;
+mod secure;
+mod shared_cache;
+mod shred;
+mod stats;
+mod tokenize;
+mod trigger;
 mod vault;
 use contents::PiiTextContents;
+use keys::KeyKind;
 
 static PII_VAULT_URL: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
 static PII_VAULT_TOKEN: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
 static PII_VAULT_MOUNT: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
 static PII_VAULT_CACHE_TTL: GucSetting<i32> = GucSetting::<i32>::new(300);
+static PII_VAULT_CACHE_MAX_ENTRIES: GucSetting<i32> =
+    GucSetting::<i32>::new(shared_cache::CAPACITY as i32);
+static PII_VAULT_CACHE_PREFETCH_SEC: GucSetting<i32> = GucSetting::<i32>::new(30);
+static PII_VAULT_PREFETCH_PARALLELISM: GucSetting<i32> = GucSetting::<i32>::new(8);
+static PII_VAULT_READER_ROLE: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+static PII_VAULT_ADMIN_ROLE: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static PII_VAULT_PURPOSE: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
+static PII_VAULT_AUDIT_SINK: GucSetting<audit::Sink> =
+    GucSetting::<audit::Sink>::new(audit::Sink::None);
+static PII_VAULT_AUDIT_SYSLOG_ADDRESS: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(Some(c"127.0.0.1:514"));
+static PII_VAULT_METRICS_PORT: GucSetting<i32> = GucSetting::<i32>::new(0);
+static PII_VAULT_METRICS_LISTEN_ADDRESS: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(Some(c"127.0.0.1"));
 
 ::pgrx::pg_module_magic!(name, version);
 
@@ -53,9 +90,101 @@ pub unsafe extern "C-unwind" fn _PG_init() {
         GucContext::Userset,
         GucFlags::default(),
     );
+    GucRegistry::define_int_guc(
+        c"pii_vault.cache_max_entries",
+        c"Key cache size",
+        c"Maximum number of cached keys in each backend, and in shared memory up to its capacity of 4096",
+        &PII_VAULT_CACHE_MAX_ENTRIES,
+        0,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pii_vault.cache_prefetch_sec",
+        c"Key refresh lead time",
+        c"Seconds before expiry at which the key cache worker refreshes keys in use; 0 disables refreshing",
+        &PII_VAULT_CACHE_PREFETCH_SEC,
+        0,
+        i32::MAX,
+        GucContext::Sighup,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pii_vault.prefetch_parallelism",
+        c"Concurrent prefetch requests",
+        c"Maximum number of Vault requests pii_vault_prefetch() has in flight",
+        &PII_VAULT_PREFETCH_PARALLELISM,
+        1,
+        64,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pii_vault.reader_role",
+        c"Role allowed to decrypt",
+        c"Members of this role see plaintext, others get masked values; empty disables the check",
+        &PII_VAULT_READER_ROLE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pii_vault.admin_role",
+        c"Role allowed to administer PII",
+        c"Members of this role may call debug, raw and rotation functions; empty disables the check",
+        &PII_VAULT_ADMIN_ROLE,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pii_vault.purpose",
+        c"Purpose of processing",
+        c"Declared purpose of the session, checked against the allowed purposes of column policies",
+        &PII_VAULT_PURPOSE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_enum_guc(
+        c"pii_vault.audit_sink",
+        c"Decryption audit sink",
+        c"Where decryption events go: none, log, table or syslog",
+        &PII_VAULT_AUDIT_SINK,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pii_vault.audit_syslog_address",
+        c"Syslog address for audit events",
+        c"host:port receiving audit events over UDP when pii_vault.audit_sink is syslog",
+        &PII_VAULT_AUDIT_SYSLOG_ADDRESS,
+        GucContext::Suset,
+        GucFlags::default(),
+    );
+    GucRegistry::define_int_guc(
+        c"pii_vault.metrics_port",
+        c"Prometheus exporter port",
+        c"Port of the background worker serving /metrics; 0 disables it (requires shared_preload_libraries)",
+        &PII_VAULT_METRICS_PORT,
+        0,
+        65535,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+    GucRegistry::define_string_guc(
+        c"pii_vault.metrics_listen_address",
+        c"Prometheus exporter address",
+        c"Address the metrics exporter listens on",
+        &PII_VAULT_METRICS_LISTEN_ADDRESS,
+        GucContext::Postmaster,
+        GucFlags::default(),
+    );
+
+    stats::init();
+    exporter::init();
+    shared_cache::init();
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, PostgresType)]
 pub struct PiiText {
     inner: Vec<u8>,
 }
@@ -69,40 +198,172 @@ fn piitext_input(input: &str) -> PiiText {
 }
 
 // Custom output function - converts PiiText to readable text
-#[pg_extern(immutable, strict, name = "piitext_out_text")]
+#[pg_extern(stable, strict, name = "piitext_out_text")]
 fn piitext_output(input: PiiText) -> String {
-    let pii = PiiTextContents::from(input.inner.as_slice());
-    match pii {
-        PiiTextContents::Staging(s) => s.into_owned(),
+    if let Some(masked) = restricted_output(&input) {
+        return masked;
+    }
+    open_piitext(&input, audit::Outcome::Plaintext).unwrap_or_else(|| "****".to_string())
+}
+
+// Like piitext_out_text, but NULL instead of "****" when the value cannot be
+// decrypted (e.g. the key has been shredded)
+#[pg_extern(stable, strict)]
+fn piitext_try_out_text(input: PiiText) -> Option<String> {
+    if let Some(masked) = restricted_output(&input) {
+        return Some(masked);
+    }
+    open_piitext(&input, audit::Outcome::Plaintext)
+}
+
+// Whether the caller may see the plaintext of input: a member of reader_role
+// whose pii_vault.purpose the column policy its AAD labels point to allows.
+// Otherwise the policy is returned, if any, to mask the value with.
+fn may_read(input: &PiiText) -> Result<(), Option<Arc<policy::ColumnPolicy>>> {
+    let policy = match PiiTextContents::from(input.inner.as_slice()) {
         PiiTextContents::Sealed(sealed) => {
-            let context = format!("col:piitext:id:{}", hex::encode(&sealed.key_id));
-            let url = PII_VAULT_URL.get();
-            let is_mock = match url {
-                Some(ref u) => u.to_str().unwrap_or("").starts_with("mock://"),
-                None => false,
-            };
+            policy::policy_for(sealed.relation.as_deref(), sealed.attribute.as_deref())
+        }
+        PiiTextContents::Staging(_) => None,
+    };
 
-            let key = if is_mock {
-                Some([0u8; 32])
-            } else {
-                cache::get_cached_key(&sealed.key_id).or_else(|| {
-                    vault::get_key_from_vault(&sealed.key_id).ok().inspect(|k| {
-                        cache::insert_into_cache(
-                            sealed.key_id.clone(),
-                            *k,
-                            PII_VAULT_CACHE_TTL.get() as u64,
-                        );
-                    })
-                })
-            };
+    let purpose_allowed = match &policy {
+        Some(policy) => policy.allows(privileges::current_purpose().as_deref()),
+        None => true,
+    };
+    if purpose_allowed && privileges::is_reader() {
+        Ok(())
+    } else {
+        Err(policy)
+    }
+}
+
+// What callers that may not see the plaintext get instead (see may_read): the
+// value masked with the mask_rule of its column policy, or fully masked for
+// unbound values. None when the plaintext may be returned.
+fn restricted_output(input: &PiiText) -> Option<String> {
+    let policy = may_read(input).err()?;
+    let masked = match policy.and_then(|policy| mask::MaskRule::parse(&policy.mask_rule).ok()) {
+        Some(rule) => match open_piitext(input, audit::Outcome::Masked) {
+            Some(plaintext) => rule.apply(&Zeroizing::new(plaintext)),
+            None => mask::FULL_MASK.to_string(),
+        },
+        None => mask::FULL_MASK.to_string(),
+    };
+    Some(masked)
+}
+
+// Masked projection of the plaintext (see mask.rs); the plaintext itself is
+// never returned, and values that cannot be decrypted mask to "****". Callers
+// that may not see the plaintext get the mask of the column policy instead of
+// the requested rule, as from piitext_out_text.
+#[pg_extern(stable, strict)]
+fn piitext_mask(input: PiiText, rule: &str) -> String {
+    let rule = mask::MaskRule::parse(rule).unwrap_or_else(|e| {
+        pgrx::error!("piitext_mask: {}", e);
+    });
+    if let Some(masked) = restricted_output(&input) {
+        return masked;
+    }
+    match open_piitext(&input, audit::Outcome::Masked) {
+        Some(plaintext) => rule.apply(&Zeroizing::new(plaintext)),
+        None => mask::FULL_MASK.to_string(),
+    }
+}
+
+// Decrypt input, recording the decryption as outcome in the audit trail
+fn open_piitext(input: &PiiText, outcome: audit::Outcome) -> Option<String> {
+    match PiiTextContents::from(input.inner.as_slice()) {
+        PiiTextContents::Staging(s) => Some(s.into_owned()),
+        PiiTextContents::Sealed(sealed) => {
+            let context = sealed.aad("piitext");
+            let plaintext = keys::resolve_sealed(&sealed)
+                .ok()
+                .and_then(|key| crypto::decrypt(&sealed, &key, &context).ok());
+            match plaintext {
+                Some(_) => audit::record(&sealed, outcome),
+                None => audit::record(&sealed, audit::Outcome::Failed),
+            }
+            plaintext
+        }
+    }
+}
 
-            if let Some(k) = key {
-                crypto::decrypt(&sealed, &k, &context).unwrap_or_else(|_| "****".to_string())
-            } else {
-                "****".to_string()
+// Decrypt a batch of values in input order, e.g. for exports. Keys missing
+// from the cache are prefetched together, and each key is resolved and its
+// cipher set up once per key_id instead of once per value. Callers that may not
+// see the plaintext get masked values as from piitext_out_text; NULLs and
+// values that cannot be decrypted give NULL.
+fn open_many(inputs: Vec<Option<PiiText>>) -> Vec<Option<String>> {
+    let mut plaintexts = Vec::with_capacity(inputs.len());
+    let mut sealed_values = Vec::new();
+    for (i, input) in inputs.iter().enumerate() {
+        let Some(input) = input else {
+            plaintexts.push(None);
+            continue;
+        };
+        if let Some(masked) = restricted_output(input) {
+            plaintexts.push(Some(masked));
+            continue;
+        }
+        match PiiTextContents::from(input.inner.as_slice()) {
+            PiiTextContents::Staging(s) => plaintexts.push(Some(s.into_owned())),
+            PiiTextContents::Sealed(sealed) => {
+                plaintexts.push(None);
+                sealed_values.push((i, sealed));
             }
         }
     }
+
+    for kind in [KeyKind::Data, KeyKind::Deterministic] {
+        let key_ids: Vec<Vec<u8>> = sealed_values
+            .iter()
+            .filter(|(_, sealed)| keys::sealed_kind(sealed) == kind)
+            .map(|(_, sealed)| sealed.key_id.clone())
+            .collect();
+        // Keys that cannot be fetched fail below, one key_id at a time
+        let _ = keys::prefetch(kind, &key_ids);
+    }
+
+    let mut ciphers = HashMap::new();
+    for (i, sealed) in &sealed_values {
+        stats::count(stats::Counter::Decrypt);
+        let cipher = ciphers
+            .entry((sealed.algorithm, sealed.key_id.clone()))
+            .or_insert_with(|| {
+                keys::resolve_sealed_key(sealed)
+                    .ok()
+                    .and_then(|key| crypto::Cipher::new(sealed.algorithm, &key).ok())
+            });
+        let plaintext = cipher
+            .as_ref()
+            .and_then(|cipher| cipher.decrypt(sealed, &sealed.aad("piitext")).ok());
+        match plaintext {
+            Some(_) => audit::record(sealed, audit::Outcome::Plaintext),
+            None => audit::record(sealed, audit::Outcome::Failed),
+        }
+        plaintexts[*i] = plaintext;
+    }
+    plaintexts
+}
+
+// Bulk piitext_try_out_text: the plaintexts of an array, in order
+#[pg_extern(stable, strict)]
+fn piitext_decrypt_many(values: Vec<Option<PiiText>>) -> Vec<Option<String>> {
+    open_many(values)
+}
+
+// piitext_decrypt_many as rows, numbered from 1 like unnest ... WITH ORDINALITY
+#[pg_extern(stable, strict)]
+fn piitext_decrypt_batch(
+    values: Vec<Option<PiiText>>,
+) -> TableIterator<'static, (name!(ordinality, i64), name!(plaintext, Option<String>))> {
+    TableIterator::new(
+        open_many(values)
+            .into_iter()
+            .enumerate()
+            .map(|(i, plaintext)| (i as i64 + 1, plaintext)),
+    )
 }
 
 // Create implicit casts so piitext behaves like text
@@ -118,102 +379,266 @@ CREATE CAST (piitext AS text) WITH FUNCTION piitext_out_text(piitext) AS IMPLICI
 
 #[pg_extern]
 fn piitext_debug(input: PiiText) -> String {
+    privileges::require_admin("piitext_debug");
     let pii = PiiTextContents::from(input.inner.as_slice());
-    format!("{:?}", pii)
+    match &pii {
+        PiiTextContents::Sealed(sealed) => {
+            format!("{:?} key_id_as: {}", pii, key_id::render(&sealed.key_id))
+        }
+        PiiTextContents::Staging(_) => format!("{:?}", pii),
+    }
 }
 
 #[pg_extern]
 fn piitext_raw(input: PiiText) -> Vec<u8> {
+    privileges::require_admin("piitext_raw");
     input.inner
 }
 
 // Encrypt text with specified key_id
 #[pg_extern(immutable, strict)]
 fn piitext_encrypt(plaintext: &str, key_id_bytes: Vec<u8>) -> PiiText {
-    let url = PII_VAULT_URL.get();
-    let is_mock = match url {
-        Some(ref u) => u.to_str().unwrap_or("").starts_with("mock://"),
-        None => false,
-    };
+    seal_piitext(plaintext, key_id_bytes, None, None)
+}
 
-    let key = if is_mock {
-        [0u8; 32]
-    } else {
-        match cache::get_cached_key(&key_id_bytes) {
-            Some(k) => k,
-            None => match vault::get_key_from_vault(&key_id_bytes) {
-                Ok(k) => {
-                    cache::insert_into_cache(
-                        key_id_bytes.clone(),
-                        k,
-                        PII_VAULT_CACHE_TTL.get() as u64,
-                    );
-                    k
-                }
-                Err(e) => {
-                    pgrx::error!("Vault error: {}", e);
-                }
-            },
+// Encrypt text with specified key_id, binding the AAD to relation/attribute labels
+// of a column policy so the ciphertext cannot be moved to another column
+#[pg_extern(immutable)]
+fn piitext_encrypt_bound(
+    plaintext: &str,
+    key_id_bytes: Vec<u8>,
+    relation: Option<&str>,
+    attribute: Option<&str>,
+) -> PiiText {
+    seal_piitext(plaintext, key_id_bytes, relation, attribute)
+}
+
+// Encrypt text deterministically under the shared key of namespace, so equal
+// plaintexts give equal piitext values that can be compared and joined
+#[pg_extern(immutable, strict)]
+fn piitext_encrypt_deterministic(plaintext: &str, namespace: &str) -> PiiText {
+    seal_piitext_with(
+        plaintext,
+        namespace.as_bytes().to_vec(),
+        crypto::ALG_AES_256_GCM_SIV,
+        None,
+        None,
+    )
+}
+
+// Whether two values are the same stored bytes, compared without decrypting;
+// meaningful for deterministic (AES-GCM-SIV) values. Behind the == operator,
+// so = keeps comparing plaintext through the text cast.
+#[pg_extern(immutable, strict, parallel_safe)]
+fn piitext_sealed_eq(left: PiiText, right: PiiText) -> bool {
+    left.inner == right.inner
+}
+
+#[pg_extern(immutable, strict, parallel_safe)]
+fn piitext_sealed_hash(input: PiiText) -> i32 {
+    pgrx::misc::pgrx_seahash(&input.inner) as i32
+}
+
+// == and its hash operator class, for joins, GROUP BY and hash indexes on
+// deterministic values
+extension_sql!(
+    r#"
+CREATE OPERATOR == (
+    LEFTARG = piitext,
+    RIGHTARG = piitext,
+    FUNCTION = piitext_sealed_eq,
+    COMMUTATOR = ==,
+    RESTRICT = eqsel,
+    JOIN = eqjoinsel,
+    HASHES
+);
+CREATE OPERATOR FAMILY piitext_sealed_ops USING hash;
+CREATE OPERATOR CLASS piitext_sealed_ops DEFAULT FOR TYPE piitext USING hash FAMILY piitext_sealed_ops AS
+    OPERATOR 1 == (piitext, piitext),
+    FUNCTION 1 piitext_sealed_hash(piitext);
+"#,
+    name = "piitext_sealed_ops",
+    requires = [piitext_sealed_eq, piitext_sealed_hash]
+);
+
+fn seal_piitext(
+    plaintext: &str,
+    key_id_bytes: Vec<u8>,
+    relation: Option<&str>,
+    attribute: Option<&str>,
+) -> PiiText {
+    seal_piitext_with(
+        plaintext,
+        key_id_bytes,
+        crypto::ALG_AES_256_GCM,
+        relation,
+        attribute,
+    )
+}
+
+fn seal_piitext_with(
+    plaintext: &str,
+    key_id_bytes: Vec<u8>,
+    algorithm: u8,
+    relation: Option<&str>,
+    attribute: Option<&str>,
+) -> PiiText {
+    let key = match algorithm {
+        crypto::ALG_AES_256_GCM_SIV => keys::resolve(KeyKind::Deterministic, &key_id_bytes),
+        _ => keys::resolve_key(&key_id_bytes),
+    }
+    .unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let context = contents::aad("piitext", &key_id_bytes, relation, attribute);
+    let sealed = match algorithm {
+        crypto::ALG_AES_256_GCM_SIV => {
+            crypto::encrypt_deterministic(plaintext.as_bytes(), &key, &key_id_bytes, &context)
         }
+        _ => crypto::encrypt(plaintext, &key, &key_id_bytes, &context),
     };
-
-    let context = format!("col:piitext:id:{}", hex::encode(&key_id_bytes));
-    match crypto::encrypt(plaintext, &key, &key_id_bytes, &context) {
-        Ok(sealed) => PiiText {
-            inner: PiiTextContents::Sealed(sealed).into(),
-        },
+    match sealed {
+        Ok(mut sealed) => {
+            if relation.is_some() || attribute.is_some() {
+                sealed.version = 2;
+                sealed.relation = relation.map(str::to_owned);
+                sealed.attribute = attribute.map(str::to_owned);
+            }
+            PiiText {
+                inner: PiiTextContents::Sealed(sealed).into(),
+            }
+        }
         Err(e) => {
             pgrx::error!("Encryption failed: {}", e);
         }
     }
 }
 
-// Encrypt or re-encrypt PiiText with specified key_id
-// This allows re-encrypting already stored data with a new key
-#[pg_extern(immutable, strict, name = "piitext_encrypt_piitext")]
-fn piitext_encrypt_from_piitext(input: PiiText, key_id_bytes: Vec<u8>) -> PiiText {
-    // First, extract the plaintext from the input
-    let plaintext = match PiiTextContents::from(input.inner.as_slice()) {
-        PiiTextContents::Staging(s) => s.into_owned(),
+// Plaintext and AAD bindings of a value that is about to be sealed again; the
+// plaintext is wiped once sealed
+fn open_for_reseal(input: &PiiText) -> (Zeroizing<String>, Option<String>, Option<String>) {
+    open_or_error(input, audit::Outcome::Resealed, "re-encryption")
+}
+
+// Plaintext of a value for pii_vault.unprotect(decrypt => true): a value that
+// cannot be decrypted aborts the conversion instead of being stored as "****",
+// and so does one the caller may only see masked
+#[pg_extern(volatile, strict)]
+fn piitext_decrypt_or_error(input: PiiText) -> String {
+    privileges::require_admin("piitext_decrypt_or_error");
+    if may_read(&input).is_err() {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            "permission denied for function piitext_decrypt_or_error: the plaintext may only be read by pii_vault.reader_role with a purpose the column policy allows"
+        );
+    }
+    let (plaintext, _, _) = open_or_error(&input, audit::Outcome::Plaintext, "decryption");
+    plaintext.to_string()
+}
+
+extension_sql!(
+    r#"
+REVOKE EXECUTE ON FUNCTION piitext_decrypt_or_error(piitext) FROM PUBLIC;
+"#,
+    name = "piitext_decrypt_or_error_revoke",
+    requires = [piitext_decrypt_or_error]
+);
+
+// Decrypt input or raise an error naming operation, recording outcome
+fn open_or_error(
+    input: &PiiText,
+    outcome: audit::Outcome,
+    operation: &str,
+) -> (Zeroizing<String>, Option<String>, Option<String>) {
+    match PiiTextContents::from(input.inner.as_slice()) {
+        PiiTextContents::Staging(s) => (Zeroizing::new(s.into_owned()), None, None),
         PiiTextContents::Sealed(sealed) => {
             // Decrypt the sealed data first
-            let context = format!("col:piitext:id:{}", hex::encode(&sealed.key_id));
-            let url = PII_VAULT_URL.get();
-            let is_mock = match url {
-                Some(ref u) => u.to_str().unwrap_or("").starts_with("mock://"),
-                None => false,
-            };
+            let context = sealed.aad("piitext");
+            let key = keys::resolve_sealed(&sealed).ok();
 
-            let key = if is_mock {
-                Some([0u8; 32])
-            } else {
-                cache::get_cached_key(&sealed.key_id).or_else(|| {
-                    vault::get_key_from_vault(&sealed.key_id).ok().inspect(|k| {
-                        cache::insert_into_cache(
-                            sealed.key_id.clone(),
-                            *k,
-                            PII_VAULT_CACHE_TTL.get() as u64,
-                        );
-                    })
-                })
-            };
-
-            match key {
+            let plaintext = match key {
                 Some(k) => match crypto::decrypt(&sealed, &k, &context) {
-                    Ok(p) => p,
+                    Ok(p) => Zeroizing::new(p),
                     Err(e) => {
-                        pgrx::error!("Decryption failed during re-encryption: {}", e);
+                        audit::record(&sealed, audit::Outcome::Failed);
+                        pgrx::error!("Decryption failed during {}: {}", operation, e);
                     }
                 },
                 None => {
-                    pgrx::error!("Key not found for decryption during re-encryption");
+                    audit::record(&sealed, audit::Outcome::Failed);
+                    pgrx::error!("Key not found for decryption during {}", operation);
                 }
-            }
+            };
+            audit::record(&sealed, outcome);
+            (plaintext, sealed.relation, sealed.attribute)
         }
+    }
+}
+
+// Encrypt or re-encrypt PiiText with specified key_id
+// This allows re-encrypting already stored data with a new key
+#[pg_extern(immutable, strict, name = "piitext_encrypt_piitext")]
+fn piitext_encrypt_from_piitext(input: PiiText, key_id_bytes: Vec<u8>) -> PiiText {
+    privileges::require_admin("piitext_encrypt_piitext");
+    // First, extract the plaintext from the input
+    let (plaintext, relation, attribute) = open_for_reseal(&input);
+
+    // Now encrypt with the new key_id, keeping the column binding
+    seal_piitext(
+        &plaintext,
+        key_id_bytes,
+        relation.as_deref(),
+        attribute.as_deref(),
+    )
+}
+
+// Re-seal a value under the same key_id with new AAD bindings (NULL labels
+// remove them); migrates rows written before a column policy bound its AAD.
+// Labels decide which policy masks a value, so only admins may rebind, and only
+// to labels of an existing policy.
+#[pg_extern(volatile)]
+fn piitext_rebind(input: PiiText, relation: Option<&str>, attribute: Option<&str>) -> PiiText {
+    privileges::require_admin("piitext_rebind");
+    if (relation.is_some() || attribute.is_some())
+        && policy::policy_for(relation, attribute).is_none()
+    {
+        pgrx::error!(
+            "piitext_rebind: no column policy has the AAD labels ({}, {})",
+            relation.unwrap_or("NULL"),
+            attribute.unwrap_or("NULL")
+        );
+    }
+    let sealed_with = match PiiTextContents::from(input.inner.as_slice()) {
+        PiiTextContents::Staging(_) => None,
+        PiiTextContents::Sealed(sealed) => Some((
+            sealed.key_id,
+            sealed.algorithm.unwrap_or(crypto::ALG_AES_256_GCM),
+        )),
     };
 
-    // Now encrypt with the new key_id
-    piitext_encrypt(&plaintext, key_id_bytes)
+    // Keep key and algorithm, so deterministic values stay deterministic
+    match sealed_with {
+        Some((key_id_bytes, algorithm)) => {
+            let (plaintext, _, _) = open_for_reseal(&input);
+            seal_piitext_with(&plaintext, key_id_bytes, algorithm, relation, attribute)
+        }
+        None => input,
+    }
+}
+
+// Whether a value is sealed with exactly these AAD bindings (staging values are
+// unbound, so only match NULL labels)
+#[pg_extern(immutable)]
+fn piitext_bound_to(input: PiiText, relation: Option<&str>, attribute: Option<&str>) -> bool {
+    match PiiTextContents::from(input.inner.as_slice()) {
+        PiiTextContents::Staging(_) => relation.is_none() && attribute.is_none(),
+        PiiTextContents::Sealed(sealed) => {
+            sealed.relation.as_deref() == relation && sealed.attribute.as_deref() == attribute
+        }
+    }
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -357,6 +782,915 @@ mod tests {
         // Cleanup
         Spi::run("DROP TABLE reencrypt_test;").unwrap();
     }
+
+    #[pg_test]
+    fn test_piibytea_binary_roundtrip() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        // Not valid UTF-8, would be rejected by the piitext path
+        let decrypted = Spi::get_one::<Vec<u8>>(
+            "SELECT piibytea_out_bytea(piibytea_encrypt(decode('00ff10c3', 'hex'), decode('0000007b', 'hex')))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(decrypted, vec![0x00, 0xff, 0x10, 0xc3]);
+
+        let debug = Spi::get_one::<&str>(
+            "SELECT piibytea_debug(piibytea_encrypt(decode('00ff10c3', 'hex'), decode('0000007b', 'hex')))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(debug.contains("Sealed"));
+        assert!(debug.contains("key_id: [0, 0, 0, 123]"));
+
+        // Varlena types default to EXTENDED storage, so large payloads are TOASTed
+        let storage =
+            Spi::get_one::<&str>("SELECT typstorage::text FROM pg_type WHERE typname = 'piibytea'")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_eq!(storage, "x");
+    }
+
+    #[pg_test]
+    fn test_typed_scalars() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        // Implicit piidate -> date cast lets date arithmetic work directly
+        let days = Spi::get_one::<i32>(
+            "SELECT DATE '2000-01-11' - piidate_encrypt(DATE '2000-01-01', decode('0000007b', 'hex'))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(days, 10);
+
+        let salary = Spi::get_one::<&str>(
+            "SELECT piinumeric_out_numeric(piinumeric_encrypt(12345.67, decode('0000007b', 'hex')))::text",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(salary, "12345.67");
+
+        let national_id = Spi::get_one::<i64>(
+            "SELECT piiint8_encrypt(-9000000000, decode('0000007b', 'hex')) + 0",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(national_id, -9000000000);
+    }
+
+    #[pg_test]
+    fn test_native_key_id_derivation() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        // Matches the legacy lpad(to_hex(id)) encoding, including negatives
+        let int_key = Spi::get_one::<Vec<u8>>("SELECT pii_key_id(-2)")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(int_key, vec![0xff, 0xff, 0xff, 0xfe]);
+
+        let bigint_key = Spi::get_one::<Vec<u8>>("SELECT pii_key_id(123::int8)")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(bigint_key, vec![0, 0, 0, 0, 0, 0, 0, 123]);
+
+        let uuid_matches = Spi::get_one::<bool>(
+            "SELECT pii_key_id('a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'::uuid) = uuid_send('a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11'::uuid)",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(uuid_matches);
+
+        let debug =
+            Spi::get_one::<&str>("SELECT piitext_debug(piitext_encrypt('int secret', 123))")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert!(debug.contains("key_id: [0, 0, 0, 123]"));
+        assert!(debug.contains("key_id_as: int4 123"));
+
+        // An untyped key_id literal is not taken for a text subject id
+        assert!(Spi::run("SELECT piitext_encrypt('x', '\\x0102')").is_err());
+        let bytea_id =
+            Spi::get_one::<&str>("SELECT piitext_debug(piitext_encrypt('x', '\\x0102'::bytea))")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert!(bytea_id.contains("key_id_as: bytea \\x0102"));
+
+        let decrypted = Spi::get_one::<&str>(
+            "SELECT piitext_out_text(piitext_encrypt_text_id('text secret', 'user-42'))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(decrypted, "text secret");
+    }
+
+    #[pg_test]
+    fn test_encrypt_trigger() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        Spi::run("CREATE TABLE trigger_test (id INT, email piitext, phone piitext);").unwrap();
+        Spi::run(
+            "CREATE TRIGGER trigger_test_pii BEFORE INSERT OR UPDATE ON trigger_test \
+             FOR EACH ROW EXECUTE FUNCTION pii_vault_encrypt_trigger('id', 'email', 'phone');",
+        )
+        .unwrap();
+
+        // Application writes plaintext, the trigger seals it with the row's key
+        Spi::run("INSERT INTO trigger_test VALUES (7, 'a@example.com'::text, NULL);").unwrap();
+        Spi::run("UPDATE trigger_test SET phone = '+15550100'::text WHERE id = 7;").unwrap();
+
+        let debug =
+            Spi::get_one::<&str>("SELECT piitext_debug(email) FROM trigger_test WHERE id = 7;")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert!(debug.contains("Sealed"));
+        assert!(debug.contains("key_id: [0, 0, 0, 7]"));
+
+        let phone =
+            Spi::get_one::<&str>("SELECT piitext_out_text(phone) FROM trigger_test WHERE id = 7;")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_eq!(phone, "+15550100");
+
+        Spi::run("DROP TABLE trigger_test;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_column_policy_protect() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        Spi::run("CREATE TABLE policy_test (id INT, ssn TEXT);").unwrap();
+        Spi::run("INSERT INTO policy_test VALUES (5, '078-05-1120');").unwrap();
+        Spi::run("SELECT pii_vault.protect('policy_test', 'ssn', 'id', 'hr');").unwrap();
+
+        // Existing rows are sealed under the namespaced key of their subject
+        let debug =
+            Spi::get_one::<&str>("SELECT piitext_debug(ssn) FROM policy_test WHERE id = 5;")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert!(debug.contains("Sealed"));
+        assert!(debug.contains("key_id: [104, 114, 58, 0, 0, 0, 5]"));
+
+        Spi::run("INSERT INTO policy_test VALUES (6, '219-09-9999'::text);").unwrap();
+        let ssn =
+            Spi::get_one::<&str>("SELECT piitext_out_text(ssn) FROM policy_test WHERE id = 6;")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_eq!(ssn, "219-09-9999");
+
+        let policies = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pii_vault.column_policy WHERE relid = 'policy_test'::regclass",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(policies, 1);
+
+        Spi::run("SELECT pii_vault.unprotect('policy_test', 'ssn', decrypt => true);").unwrap();
+        let ssn = Spi::get_one::<&str>("SELECT ssn FROM policy_test WHERE id = 5;")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(ssn, "078-05-1120");
+
+        Spi::run("DROP TABLE policy_test;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_aad_bound_to_column() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        Spi::run("CREATE TABLE aad_test (id INT, ssn piitext);").unwrap();
+        // Written before the policy existed: legacy, unbound AAD
+        Spi::run("INSERT INTO aad_test VALUES (1, piitext_encrypt('078-05-1120', 1));").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('aad_test', 'ssn', 'id', aad_relation => 'people', aad_attribute => 'ssn');",
+        )
+        .unwrap();
+
+        // protect() migrated the existing row to the bound AAD
+        let bound = Spi::get_one::<bool>(
+            "SELECT piitext_bound_to(ssn, 'people', 'ssn') FROM aad_test WHERE id = 1;",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(bound);
+
+        let debug = Spi::get_one::<&str>("SELECT piitext_debug(ssn) FROM aad_test WHERE id = 1;")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert!(debug.contains("version: 2"));
+
+        let ssn = Spi::get_one::<&str>("SELECT piitext_out_text(ssn) FROM aad_test WHERE id = 1;")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(ssn, "078-05-1120");
+
+        Spi::run("DROP TABLE aad_test;").unwrap();
+    }
+
+    #[pg_test(
+        error = "pii_vault_encrypt_trigger: value for column \"nickname\" is sealed for another column"
+    )]
+    fn test_aad_rejects_column_swap() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        Spi::run("CREATE TABLE swap_test (id INT, ssn piitext, nickname piitext);").unwrap();
+        Spi::run("SELECT pii_vault.protect('swap_test', 'ssn', 'id', aad_relation => 'people', aad_attribute => 'ssn');").unwrap();
+        Spi::run("SELECT pii_vault.protect('swap_test', 'nickname', 'id', aad_relation => 'people', aad_attribute => 'nickname');").unwrap();
+        Spi::run("INSERT INTO swap_test VALUES (1, '078-05-1120'::text, 'bob'::text);").unwrap();
+
+        // Copying the ssn ciphertext into the nickname column must fail
+        Spi::run("UPDATE swap_test SET nickname = ssn;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_blind_index_lookup() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let len =
+            Spi::get_one::<i32>("SELECT length(pii_blind_index('x@y.com', 'email', bits => 64))")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_eq!(len, 8);
+
+        Spi::run("CREATE TABLE bidx_test (id INT, email piitext, email_bidx bytea);").unwrap();
+        Spi::run("CREATE INDEX bidx_test_email ON bidx_test (email_bidx);").unwrap();
+        Spi::run("INSERT INTO bidx_test VALUES \
+                  (1, piitext_encrypt('Alice@Example.com', 1), pii_blind_index('Alice@Example.com', 'email')), \
+                  (2, piitext_encrypt('bob@example.com', 2), pii_blind_index('bob@example.com', 'email'));")
+            .unwrap();
+
+        // Decrypted output depends on the session, so it cannot be indexed
+        assert!(Spi::run(
+            "CREATE INDEX bidx_test_expr ON bidx_test (pii_blind_index(email, 'email'));"
+        )
+        .is_err());
+
+        // Normalization makes the lookup case and whitespace insensitive
+        let id = Spi::get_one::<i32>(
+            "SELECT id FROM bidx_test WHERE email_bidx = pii_blind_index(' alice@example.com', 'email')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(id, 1);
+
+        // Computing blind indexes is an oracle for guessed values
+        let public = Spi::get_one::<bool>(
+            "SELECT has_function_privilege('public', 'pii_blind_index(text, text, text, integer)', 'EXECUTE')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(!public);
+
+        Spi::run("DROP TABLE bidx_test;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_deterministic_equality() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let equal = Spi::get_one::<bool>(
+            "SELECT piitext_encrypt_deterministic('123-45-6789', 'ssn') == piitext_encrypt_deterministic('123-45-6789', 'ssn')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(equal);
+
+        Spi::run(
+            "CREATE TABLE det_a (id INT, ssn piitext); CREATE TABLE det_b (id INT, ssn piitext);",
+        )
+        .unwrap();
+        Spi::run("INSERT INTO det_a VALUES (1, 'a'), (2, 'b');").unwrap();
+        Spi::run("INSERT INTO det_b VALUES (10, 'b');").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('det_a', 'ssn', 'id', key_namespace => 'ssn', algorithm => 'aes-256-gcm-siv'); \
+             SELECT pii_vault.protect('det_b', 'ssn', 'id', key_namespace => 'ssn', algorithm => 'aes-256-gcm-siv');",
+        )
+        .unwrap();
+
+        // Same plaintext and namespace seal to the same bytes, so rows join
+        // without decrypting, and the values still decrypt
+        let joined = Spi::get_one::<String>(
+            "SELECT a.ssn::text FROM det_a a JOIN det_b b ON a.ssn == b.ssn",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(joined, "b");
+
+        Spi::run("DROP TABLE det_a; DROP TABLE det_b;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_format_preserving_encryption() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let encrypted =
+            Spi::get_one::<String>("SELECT pii_fpe_encrypt('+1 (555) 010-9999', pii_key_id(7))")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_ne!(encrypted, "+1 (555) 010-9999");
+        assert_eq!(encrypted.len(), "+1 (555) 010-9999".len());
+        // Separators stay in place, digits stay digits
+        assert!(encrypted
+            .chars()
+            .zip("+1 (555) 010-9999".chars())
+            .all(
+                |(e, p)| e.is_ascii_digit() == p.is_ascii_digit() && (p.is_ascii_digit() || e == p)
+            ));
+
+        let decrypted = Spi::get_one::<String>(&format!(
+            "SELECT pii_fpe_decrypt('{}', pii_key_id(7))",
+            encrypted
+        ))
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(decrypted, "+1 (555) 010-9999");
+
+        // The tweak is the key_id: other subjects get other ciphertexts
+        let other =
+            Spi::get_one::<String>("SELECT pii_fpe_encrypt('+1 (555) 010-9999', pii_key_id(8))")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_ne!(encrypted, other);
+
+        let code = Spi::get_one::<String>(
+            "SELECT pii_fpe_decrypt(pii_fpe_encrypt('AB12-CD34', pii_key_id(7), 'alphanumeric'), pii_key_id(7), 'alphanumeric')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(code, "AB12-CD34");
+    }
+
+    #[pg_test]
+    fn test_tokenize_roundtrip() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let token = Spi::get_one::<String>(
+            "SELECT pii_tokenize('alice@example.com', 'email', pii_key_id(1))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(token.starts_with("tok_"));
+
+        // Stable per namespace, different across namespaces
+        let again = Spi::get_one::<String>(
+            "SELECT pii_tokenize('alice@example.com', 'email', pii_key_id(1))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(token, again);
+        let other = Spi::get_one::<String>("SELECT pii_token('alice@example.com', 'crm')")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_ne!(token, other);
+
+        // NULL values have no token; a NULL key_id falls back to the namespace key
+        let none = Spi::get_one::<String>("SELECT pii_tokenize(NULL, 'email', pii_key_id(1))")
+            .expect("SPI failed");
+        assert_eq!(none, None);
+        let unbound =
+            Spi::get_one::<String>("SELECT pii_tokenize('bob@example.com', 'email', NULL)")
+                .expect("SPI failed");
+        assert!(unbound.is_some());
+
+        let value = Spi::get_one::<String>(&format!("SELECT pii_detokenize('{}', 'email')", token))
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(value, "alice@example.com");
+
+        // Forgetting the subject invalidates its tokens
+        let forgotten = Spi::get_one::<i64>("SELECT pii_vault.forget_tokens(pii_key_id(1))")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(forgotten, 1);
+        let value = Spi::get_one::<String>(&format!("SELECT pii_detokenize('{}', 'email')", token))
+            .expect("SPI failed");
+        assert_eq!(value, None);
+
+        // Computing tokens is an oracle for guessed values
+        let public = Spi::get_one::<bool>(
+            "SELECT has_function_privilege('public', 'pii_tokenize(text, text, bytea)', 'EXECUTE') \
+                 OR has_function_privilege('public', 'pii_token(text, text)', 'EXECUTE')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(!public);
+    }
+
+    #[pg_test]
+    fn test_pseudonym_formats() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("INSERT INTO pii_vault.pseudonym_project VALUES ('churn', current_user);")
+            .unwrap();
+
+        let stable = Spi::get_one::<bool>(
+            "SELECT pii_pseudonym(pii_key_id(1), 'churn') = pii_pseudonym(pii_key_id(1), 'churn')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(stable);
+
+        let hex = Spi::get_one::<String>("SELECT pii_pseudonym(pii_key_id(1), 'churn')")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(hex.len(), 32);
+
+        // Must parse as uuid and bigint
+        let uuid = Spi::get_one::<bool>(
+            "SELECT pii_pseudonym(pii_key_id(1), 'churn', 'uuid')::uuid IS NOT NULL",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(uuid);
+        let integer =
+            Spi::get_one::<i64>("SELECT pii_pseudonym(pii_key_id(1), 'churn', 'integer')::bigint")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert!(integer >= 0);
+
+        let distinct = Spi::get_one::<bool>(
+            "SELECT pii_pseudonym(pii_key_id(1), 'churn') <> pii_pseudonym(pii_key_id(2), 'churn')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(distinct);
+
+        // Shredding the project key takes effect at commit
+        Spi::run("SELECT pii_pseudonym_shred('churn')").unwrap();
+        let public = Spi::get_one::<bool>(
+            "SELECT has_function_privilege('public', 'pii_pseudonym(bytea, text, text)', 'EXECUTE') \
+                 OR has_function_privilege('public', 'pii_pseudonym_shred(text)', 'EXECUTE')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(!public);
+    }
+
+    #[pg_test(
+        error = "permission denied for pseudonym project \"churn\": requires membership in role \"pii_test_analyst\""
+    )]
+    fn test_pseudonym_requires_project_role() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("CREATE ROLE pii_test_analyst; CREATE ROLE pii_test_marketing;").unwrap();
+        Spi::run("INSERT INTO pii_vault.pseudonym_project VALUES ('churn', 'pii_test_analyst');")
+            .unwrap();
+        Spi::run(
+            "GRANT EXECUTE ON FUNCTION pii_pseudonym(bytea, text, text) TO pii_test_marketing; \
+             SET ROLE pii_test_marketing;",
+        )
+        .unwrap();
+
+        Spi::run("SELECT pii_pseudonym(pii_key_id(1), 'churn')").unwrap();
+    }
+
+    #[pg_test]
+    fn test_piitext_mask_rules() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let cases = [
+            ("john@example.com", "email", "j***@example.com"),
+            ("+1 (555) 010-9999", "phone", "+* (***) ***-9999"),
+            ("4111 1111 1111 1234", "card", "**** **** **** 1234"),
+            ("John Smith", "name", "J."),
+            ("secret", "keep_first:2", "se***"),
+            ("secret", "keep_last:2", "***et"),
+            ("ab", "keep_last:2", "****"),
+            ("AB-1234", "regex:[0-9]", "AB-****"),
+            ("anything", "full", "****"),
+            ("secret", "keep_first:100000", "****"),
+            ("secret", "regex:$^", "****"),
+        ];
+        for (plaintext, rule, expected) in cases {
+            let masked = Spi::get_one::<String>(&format!(
+                "SELECT piitext_mask(piitext_encrypt('{}', pii_key_id(1)), '{}')",
+                plaintext, rule
+            ))
+            .expect("SPI failed")
+            .expect("Result is null");
+            assert_eq!(masked, expected, "rule {}", rule);
+        }
+    }
+
+    #[pg_test(error = "piitext_rebind: no column policy has the AAD labels (people, ssn)")]
+    fn test_rebind_requires_policy() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("SELECT piitext_rebind(piitext_encrypt('x', pii_key_id(1)), 'people', 'ssn');")
+            .unwrap();
+    }
+
+    #[pg_test(
+        error = "permission denied for function piitext_decrypt_or_error: the plaintext may only be read by pii_vault.reader_role with a purpose the column policy allows"
+    )]
+    fn test_unprotect_requires_allowed_purpose() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("CREATE TABLE unprotect_test (id INT, email text);").unwrap();
+        Spi::run("INSERT INTO unprotect_test VALUES (1, 'john@example.com');").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('unprotect_test', 'email', 'id', \
+                                      aad_relation => 'unprotect_test', aad_attribute => 'email', \
+                                      allowed_purposes => ARRAY['billing']);",
+        )
+        .unwrap();
+
+        // No pii_vault.purpose: decrypting would bypass the policy
+        Spi::run("SELECT pii_vault.unprotect('unprotect_test', 'email', decrypt => true);")
+            .unwrap();
+    }
+
+    #[pg_test(error = "mask_rule and allowed_purposes require aad_relation or aad_attribute")]
+    fn test_protect_requires_labels_for_mask() {
+        Spi::run("CREATE TABLE unlabeled_test (id INT, email text);").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('unlabeled_test', 'email', 'id', mask_rule => 'email');",
+        )
+        .unwrap();
+    }
+
+    #[pg_test(
+        error = "piitext_mask: unknown mask rule \"bogus\", expected full, email, phone, card, name, keep_first:N, keep_last:N or regex:<pattern>"
+    )]
+    fn test_piitext_mask_rejects_unknown_rule() {
+        Spi::run("SELECT piitext_mask(piitext_in_text('x'), 'bogus');").unwrap();
+    }
+
+    #[pg_test]
+    fn test_reader_role_masks_output() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("CREATE ROLE pii_test_reader; CREATE ROLE pii_test_clerk;").unwrap();
+        Spi::run("CREATE TABLE priv_test (id INT, email text, notes text);").unwrap();
+        Spi::run("INSERT INTO priv_test VALUES (1, 'john@example.com', 'private');").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('priv_test', 'email', 'id', mask_rule => 'email', \
+                                      aad_relation => 'priv_test', aad_attribute => 'email'); \
+             SELECT pii_vault.protect('priv_test', 'notes', 'id');",
+        )
+        .unwrap();
+        Spi::run("GRANT SELECT ON priv_test TO pii_test_clerk;").unwrap();
+        Spi::run("SET pii_vault.reader_role = 'pii_test_reader';").unwrap();
+
+        // Non-members get the policy's mask, or **** without one
+        Spi::run("SET ROLE pii_test_clerk;").unwrap();
+        let (email, notes) = Spi::get_two::<String, String>(
+            "SELECT email::text, notes::text FROM priv_test WHERE id = 1",
+        )
+        .expect("SPI failed");
+        Spi::run("RESET ROLE;").unwrap();
+        assert_eq!(email.as_deref(), Some("j***@example.com"));
+        assert_eq!(notes.as_deref(), Some("****"));
+
+        Spi::run("GRANT pii_test_reader TO pii_test_clerk; SET ROLE pii_test_clerk;").unwrap();
+        let email = Spi::get_one::<String>("SELECT email::text FROM priv_test WHERE id = 1")
+            .expect("SPI failed");
+        Spi::run("RESET ROLE;").unwrap();
+        assert_eq!(email.as_deref(), Some("john@example.com"));
+
+        Spi::run("RESET pii_vault.reader_role; DROP TABLE priv_test;").unwrap();
+        Spi::run("DROP ROLE pii_test_clerk; DROP ROLE pii_test_reader;").unwrap();
+    }
+
+    #[pg_test(
+        error = "permission denied for function piitext_debug: requires membership in role \"pii_test_admin\""
+    )]
+    fn test_admin_role_required_for_debug() {
+        Spi::run("CREATE ROLE pii_test_admin; CREATE ROLE pii_test_support;").unwrap();
+        Spi::run("SET pii_vault.admin_role = 'pii_test_admin'; SET ROLE pii_test_support;")
+            .unwrap();
+        Spi::run("SELECT piitext_debug(piitext_in_text('x'));").unwrap();
+    }
+
+    #[pg_test]
+    fn test_purpose_bound_decryption() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("CREATE TABLE purpose_test (id INT, email text);").unwrap();
+        Spi::run("INSERT INTO purpose_test VALUES (1, 'john@example.com');").unwrap();
+        Spi::run(
+            "SELECT pii_vault.protect('purpose_test', 'email', 'id', mask_rule => 'email', \
+                                      aad_relation => 'purpose_test', aad_attribute => 'email', \
+                                      allowed_purposes => ARRAY['billing', 'support']);",
+        )
+        .unwrap();
+
+        let read = |purpose: &str| {
+            Spi::run(&format!("SET pii_vault.purpose = '{}';", purpose)).unwrap();
+            Spi::get_one::<String>("SELECT email::text FROM purpose_test WHERE id = 1")
+                .expect("SPI failed")
+                .expect("Result is null")
+        };
+        assert_eq!(read(""), "j***@example.com");
+        assert_eq!(read("marketing"), "j***@example.com");
+        assert_eq!(read("billing"), "john@example.com");
+        assert_eq!(read("support"), "john@example.com");
+
+        Spi::run("RESET pii_vault.purpose; DROP TABLE purpose_test;").unwrap();
+    }
+
+    #[pg_test]
+    fn test_audit_table_aggregates_statement() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("CREATE TABLE audit_test (id INT, email piitext);").unwrap();
+        Spi::run(
+            "INSERT INTO audit_test SELECT 1, piitext_encrypt('a@example.com', pii_key_id(77)) \
+             FROM generate_series(1, 3);",
+        )
+        .unwrap();
+
+        Spi::run("SET pii_vault.audit_sink = 'table'; SET pii_vault.purpose = 'support';").unwrap();
+        Spi::run("SELECT email::text FROM audit_test;").unwrap();
+        Spi::run("SELECT piitext_mask(email, 'email') FROM audit_test LIMIT 1;").unwrap();
+        Spi::run("SELECT pii_vault_audit_flush();").unwrap();
+
+        // All decryptions of the statement collapse into one row per outcome
+        let (events, purpose, same_role) = Spi::get_three::<i64, String, bool>(
+            "SELECT events, purpose, session_role = session_user FROM pii_vault.audit_log \
+             WHERE key_id = pii_key_id(77) AND outcome = 'plaintext'",
+        )
+        .expect("SPI failed");
+        assert_eq!(events, Some(3));
+        assert_eq!(purpose.as_deref(), Some("support"));
+        assert_eq!(same_role, Some(true));
+
+        // Unknown sinks are rejected rather than logged
+        assert!(Spi::run("SET pii_vault.audit_sink = 'tabel';").is_err());
+        let masked = Spi::get_one::<i64>(
+            "SELECT sum(events)::bigint FROM pii_vault.audit_log WHERE key_id = pii_key_id(77) AND outcome = 'masked'",
+        )
+        .expect("SPI failed");
+        assert_eq!(masked, Some(1));
+
+        // Format-preserving decryption is audited too, without AAD labels
+        Spi::run(
+            "SELECT pii_fpe_decrypt(pii_fpe_encrypt('555-0100', pii_key_id(78)), pii_key_id(78)); \
+             SELECT pii_vault_audit_flush();",
+        )
+        .unwrap();
+        let (events, relation) = Spi::get_two::<i64, String>(
+            "SELECT events, relation FROM pii_vault.audit_log \
+             WHERE key_id = pii_key_id(78) AND outcome = 'plaintext'",
+        )
+        .expect("SPI failed");
+        assert_eq!(events, Some(1));
+        assert_eq!(relation, None);
+
+        Spi::run("RESET pii_vault.audit_sink; RESET pii_vault.purpose; DROP TABLE audit_test;")
+            .unwrap();
+    }
+
+    #[pg_test]
+    fn test_shred_ledger_chain() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+
+        let first = Spi::get_one::<i64>("SELECT pii_shred(pii_key_id(901), 'erasure request #1')")
+            .expect("SPI failed")
+            .expect("Result is null");
+        let second = Spi::get_one::<i64>("SELECT pii_shred(pii_key_id(902))")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(second, first + 1);
+        assert!(Spi::run("SELECT pii_shred(NULL)").is_err());
+
+        let public = Spi::get_one::<bool>(
+            "SELECT has_function_privilege('public', 'pii_shred(bytea, text)', 'EXECUTE')",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(!public);
+
+        let vault = Spi::get_one_with_args::<String>(
+            "SELECT vault FROM pii_vault.shred_ledger WHERE id = $1",
+            &[first.into()],
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(vault, "mock://localhost/transit");
+
+        let intact = Spi::get_one::<bool>(
+            "SELECT NOT EXISTS (SELECT 1 FROM pii_vault.verify_shred_ledger())",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(intact);
+
+        let (erased, events) = Spi::get_two::<bool, i32>(
+            "SELECT (c->>'erased')::bool, jsonb_array_length(c->'events') \
+             FROM pii_vault.erasure_certificate(pii_key_id(901)) c",
+        )
+        .expect("SPI failed");
+        assert_eq!(erased, Some(true));
+        assert_eq!(events, Some(1));
+
+        // The ledger refuses changes; with triggers bypassed, verification catches them
+        let refused = Spi::run("UPDATE pii_vault.shred_ledger SET reason = NULL");
+        assert!(refused.is_err());
+        Spi::run(&format!(
+            "SET session_replication_role = replica; \
+             UPDATE pii_vault.shred_ledger SET reason = 'routine cleanup' WHERE id = {}; \
+             RESET session_replication_role;",
+            first
+        ))
+        .unwrap();
+        let broken = Spi::get_one::<i64>(
+            "SELECT id FROM pii_vault.verify_shred_ledger() WHERE problem LIKE 'row modified%'",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(broken, first);
+    }
+
+    #[pg_test]
+    fn test_stat_view_counts() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        Spi::run("SELECT pg_stat_pii_vault_reset();").unwrap();
+
+        Spi::run("SELECT piitext_out_text(piitext_encrypt('counted', pii_key_id(31)))").unwrap();
+
+        let (encrypted, decrypted) = Spi::get_two::<i64, i64>(
+            "SELECT sum(value) FILTER (WHERE name = 'encrypt_calls')::bigint, \
+                    sum(value) FILTER (WHERE name = 'decrypt_calls')::bigint \
+             FROM pg_stat_pii_vault",
+        )
+        .expect("SPI failed");
+        assert!(encrypted.unwrap_or(0) >= 1);
+        assert!(decrypted.unwrap_or(0) >= 1);
+
+        let shared =
+            Spi::get_one::<i64>("SELECT value FROM pg_stat_pii_vault WHERE name = 'shared_memory'")
+                .expect("SPI failed")
+                .expect("Result is null");
+        assert_eq!(shared, 1);
+    }
+
+    #[pg_test]
+    fn test_key_cache_worker_running() {
+        let workers = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_stat_activity WHERE backend_type = 'pii_vault key cache'",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(workers, 1);
+    }
+
+    #[pg_test]
+    fn test_metrics_exposition() {
+        let page = Spi::get_one::<String>("SELECT pii_vault_metrics()")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert!(page.contains("# TYPE pii_vault_cache_hits_total counter\n"));
+        assert!(page.contains("pii_vault_stats_shared 1\n"));
+        assert!(page.contains("# TYPE pii_vault_vault_request_duration_seconds histogram\n"));
+        assert!(page.contains(
+            "pii_vault_vault_request_duration_seconds_bucket{endpoint=\"export\",le=\"0.005\"} "
+        ));
+        assert!(page.contains(
+            "pii_vault_vault_request_duration_seconds_bucket{endpoint=\"delete\",le=\"+Inf\"} "
+        ));
+        assert!(
+            page.contains("pii_vault_vault_request_duration_seconds_count{endpoint=\"create\"} ")
+        );
+    }
+
+    #[pg_test]
+    fn test_cache_management_functions() {
+        let before = Spi::get_one::<i64>("SELECT generation FROM pii_vault_cache_info()")
+            .expect("SPI failed")
+            .expect("Result is null");
+        let flushed = Spi::get_one::<i64>("SELECT pii_vault_cache_flush()")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert!(flushed >= 0);
+        let evicted = Spi::get_one::<bool>("SELECT pii_vault_cache_evict(pii_key_id(1))")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert!(!evicted);
+
+        let (entries, after) = Spi::get_two::<i64, i64>(
+            "SELECT shared_entries, generation FROM pii_vault_cache_info()",
+        )
+        .expect("SPI failed");
+        assert_eq!(entries, Some(0));
+        assert!(after.expect("Result is null") > before);
+    }
+
+    #[pg_test]
+    fn test_cache_follows_provider() {
+        let contains = |key_id: &str| {
+            Spi::get_one::<bool>(&format!("SELECT pii_vault_cache_test_contains({})", key_id))
+                .expect("SPI failed")
+                .expect("Result is null")
+        };
+        let backend_entries = || {
+            Spi::get_one::<i64>("SELECT backend_entries FROM pii_vault_cache_info()")
+                .expect("SPI failed")
+                .expect("Result is null")
+        };
+        // One key_id for the shared cache, one too long for it
+        let shared = "pii_key_id(7)";
+        let local = "decode(repeat('ab', 65), 'hex')";
+
+        Spi::run(
+            "SET pii_vault.url = 'http://vault.invalid:8200'; \
+             SET pii_vault.token = 'tenant-a-token'; \
+             SET pii_vault.mount = 'tenant_a';",
+        )
+        .unwrap();
+        Spi::run(&format!("SELECT pii_vault_cache_test_insert({})", shared)).unwrap();
+        Spi::run(&format!("SELECT pii_vault_cache_test_insert({})", local)).unwrap();
+        assert!(contains(shared));
+        assert!(contains(local));
+        assert_eq!(backend_entries(), 1);
+
+        // Another mount misses both and drops the backend's entry
+        Spi::run("SET pii_vault.mount = 'tenant_b';").unwrap();
+        assert!(!contains(shared));
+        assert!(!contains(local));
+        assert_eq!(backend_entries(), 0);
+
+        // The shared entry is kept for its own provider; the backend entry is gone
+        Spi::run("SET pii_vault.mount = 'tenant_a';").unwrap();
+        assert!(contains(shared));
+        assert!(!contains(local));
+
+        // So is a switch of url or token
+        Spi::run("SET pii_vault.url = 'http://vault-b.invalid:8200';").unwrap();
+        assert!(!contains(shared));
+        Spi::run(
+            "SET pii_vault.url = 'http://vault.invalid:8200'; \
+             SET pii_vault.token = 'tenant-b-token';",
+        )
+        .unwrap();
+        assert!(!contains(shared));
+
+        // Shared memory outlives the test transaction
+        Spi::run("SELECT pii_vault_cache_flush()").unwrap();
+    }
+
+    #[pg_test]
+    fn test_prefetch_keys() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        // Mock keys need no fetching; NULL elements are skipped
+        let fetched = Spi::get_one::<i64>(
+            "SELECT pii_vault_prefetch(ARRAY[pii_key_id(1), pii_key_id(2), pii_key_id(1), NULL])",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(fetched, 0);
+
+        let fetched = Spi::get_one::<i64>("SELECT pii_vault_prefetch('{}'::bytea[])")
+            .expect("SPI failed")
+            .expect("Result is null");
+        assert_eq!(fetched, 0);
+
+        // Against a stubbed Vault: keys missing from the cache are fetched once
+        // and counted, cached ones are not fetched again
+        Spi::run(
+            "SET pii_vault.url = 'http://vault.invalid:8200'; SET pii_vault.token = 'test-token';",
+        )
+        .unwrap();
+        Spi::run("SELECT pii_vault_cache_test_insert(pii_key_id(1))").unwrap();
+        let fetched = Spi::get_one::<i64>(
+            "SELECT pii_vault_prefetch_test(ARRAY[pii_key_id(1), pii_key_id(2), pii_key_id(3), pii_key_id(2), NULL])",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(fetched, 2);
+        let cached = Spi::get_one::<bool>(
+            "SELECT pii_vault_cache_test_contains(pii_key_id(2)) AND pii_vault_cache_test_contains(pii_key_id(3))",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert!(cached);
+        let fetched = Spi::get_one::<i64>(
+            "SELECT pii_vault_prefetch_test(ARRAY[pii_key_id(1), pii_key_id(2), pii_key_id(3)])",
+        )
+        .expect("SPI failed")
+        .expect("Result is null");
+        assert_eq!(fetched, 0);
+
+        // Shared memory outlives the test transaction
+        Spi::run("SELECT pii_vault_cache_flush()").unwrap();
+    }
+
+    #[pg_test]
+    fn test_decrypt_many() {
+        Spi::run("SET pii_vault.url = 'mock://localhost';").unwrap();
+        let values = "ARRAY[piitext_encrypt('a', pii_key_id(1)), NULL, \
+                      piitext_encrypt('b', pii_key_id(2)), piitext_encrypt('c', pii_key_id(1)), \
+                      piitext_encrypt_deterministic('d', 'ssn'), piitext_in_text('e')]";
+
+        let plaintexts = Spi::get_one::<Vec<Option<String>>>(&format!(
+            "SELECT piitext_decrypt_many({})",
+            values
+        ))
+        .expect("SPI failed")
+        .expect("Result is null");
+        let expected = [Some("a"), None, Some("b"), Some("c"), Some("d"), Some("e")];
+        assert_eq!(plaintexts, expected.map(|p| p.map(String::from)).to_vec());
+
+        let (ordinality, plaintext) = Spi::get_two::<i64, String>(&format!(
+            "SELECT ordinality, plaintext FROM piitext_decrypt_batch({}) WHERE plaintext = 'c'",
+            values
+        ))
+        .expect("SPI failed");
+        assert_eq!(ordinality, Some(4));
+        assert_eq!(plaintext.as_deref(), Some("c"));
+    }
 }
 
 #[cfg(test)]
@@ -365,6 +1699,9 @@ pub mod pg_test {
 
     #[must_use]
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        vec!["pii_vault.url = 'mock://localhost'"]
+        vec![
+            "pii_vault.url = 'mock://localhost'",
+            "shared_preload_libraries = 'pg_pii_vault'",
+        ]
     }
 }
@@ -0,0 +1,114 @@
+// Partial-reveal masking rules, applied to decrypted plaintext inside the
+// backend so that only the masked projection is returned:
+//
+//   full          ****
+//   email         j***@example.com
+//   phone, card   every digit but the last 4 masked, separators kept
+//   name          J.
+//   keep_first:N  first N characters, then ***
+//   keep_last:N   ***, then the last N characters
+//   regex:<re>    every match of <re> replaced by *
+//
+// Values too short to mask meaningfully come out as **** rather than revealed,
+// as do values a rule leaves unchanged.
+use regex::Regex;
+
+pub const FULL_MASK: &str = "****";
+
+#[derive(Debug, Clone)]
+pub enum MaskRule {
+    Full,
+    Email,
+    LastDigits(usize),
+    Initial,
+    KeepFirst(usize),
+    KeepLast(usize),
+    Regex(Regex),
+}
+
+impl MaskRule {
+    pub fn parse(rule: &str) -> Result<MaskRule, String> {
+        let count = |n: &str| {
+            n.parse::<usize>()
+                .map_err(|_| format!("invalid character count in mask rule \"{}\"", rule))
+        };
+
+        match rule.split_once(':') {
+            None => match rule {
+                "full" => Ok(MaskRule::Full),
+                "email" => Ok(MaskRule::Email),
+                "phone" | "card" => Ok(MaskRule::LastDigits(4)),
+                "name" => Ok(MaskRule::Initial),
+                _ => Err(format!(
+                    "unknown mask rule \"{}\", expected full, email, phone, card, name, keep_first:N, keep_last:N or regex:<pattern>",
+                    rule
+                )),
+            },
+            Some(("keep_first", n)) => Ok(MaskRule::KeepFirst(count(n)?)),
+            Some(("keep_last", n)) => Ok(MaskRule::KeepLast(count(n)?)),
+            Some(("regex", pattern)) => Regex::new(pattern)
+                .map(MaskRule::Regex)
+                .map_err(|e| format!("invalid regex in mask rule: {}", e)),
+            Some(_) => Err(format!("unknown mask rule \"{}\"", rule)),
+        }
+    }
+
+    pub fn apply(&self, plaintext: &str) -> String {
+        let masked = self.project(plaintext);
+        if masked == plaintext {
+            return FULL_MASK.to_string();
+        }
+        masked
+    }
+
+    fn project(&self, plaintext: &str) -> String {
+        let chars: Vec<char> = plaintext.chars().collect();
+        match self {
+            MaskRule::Full => FULL_MASK.to_string(),
+            MaskRule::Email => match plaintext.split_once('@') {
+                Some((local, domain)) if !local.is_empty() => {
+                    let first = local.chars().next().unwrap_or('*');
+                    format!("{}***@{}", first, domain)
+                }
+                _ => FULL_MASK.to_string(),
+            },
+            MaskRule::LastDigits(keep) => {
+                let digits = chars.iter().filter(|c| c.is_ascii_digit()).count();
+                if digits <= *keep {
+                    return FULL_MASK.to_string();
+                }
+                let mut seen = 0;
+                chars
+                    .iter()
+                    .map(|c| match c.is_ascii_digit() {
+                        true => {
+                            seen += 1;
+                            if seen > digits - keep {
+                                *c
+                            } else {
+                                '*'
+                            }
+                        }
+                        false => *c,
+                    })
+                    .collect()
+            }
+            MaskRule::Initial => match chars.iter().find(|c| c.is_alphanumeric()) {
+                Some(c) => format!("{}.", c),
+                None => FULL_MASK.to_string(),
+            },
+            MaskRule::KeepFirst(n) if chars.len() > *n => {
+                format!("{}***", chars[..*n].iter().collect::<String>())
+            }
+            MaskRule::KeepLast(n) if chars.len() > *n => {
+                format!("***{}", chars[chars.len() - n..].iter().collect::<String>())
+            }
+            MaskRule::KeepFirst(_) | MaskRule::KeepLast(_) => FULL_MASK.to_string(),
+            MaskRule::Regex(re) => re
+                .replace_all(plaintext, |m: &regex::Captures| {
+                    "*".repeat(m[0].chars().count())
+                })
+                .into_owned(),
+        }
+    }
+}
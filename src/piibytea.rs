@@ -0,0 +1,76 @@
+use crate::contents::{self, PiiByteaContents};
+use crate::{audit, crypto, keys, privileges};
+use pgrx::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+pub struct PiiBytea {
+    #[serde(with = "serde_bytes")]
+    inner: Vec<u8>,
+}
+
+// Custom input function - converts bytea to PiiBytea
+#[pg_extern(immutable, strict, name = "piibytea_in_bytea")]
+fn piibytea_input(input: &[u8]) -> PiiBytea {
+    PiiBytea {
+        inner: PiiByteaContents::Staging(Cow::Borrowed(input)).into(),
+    }
+}
+
+// Custom output function - converts PiiBytea to bytea, NULL if the key is gone
+// or the current user is not a reader
+#[pg_extern(stable, strict, name = "piibytea_out_bytea")]
+fn piibytea_output(input: PiiBytea) -> Option<Vec<u8>> {
+    if !privileges::is_reader() {
+        return None;
+    }
+    match PiiByteaContents::from(input.inner.as_slice()) {
+        PiiByteaContents::Staging(b) => Some(b.into_owned()),
+        PiiByteaContents::Sealed(sealed) => {
+            let context = sealed.aad("piibytea");
+            let plaintext = keys::resolve_sealed(&sealed)
+                .ok()
+                .and_then(|key| crypto::decrypt_bytes(&sealed, &key, &context).ok());
+            match plaintext {
+                Some(_) => audit::record(&sealed, audit::Outcome::Plaintext),
+                None => audit::record(&sealed, audit::Outcome::Failed),
+            }
+            plaintext
+        }
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE CAST (bytea AS piibytea) WITH FUNCTION piibytea_in_bytea(bytea) AS IMPLICIT;
+CREATE CAST (piibytea AS bytea) WITH FUNCTION piibytea_out_bytea(piibytea) AS IMPLICIT;
+"#,
+    name = "piibytea_casts",
+    requires = [piibytea_input, piibytea_output]
+);
+
+#[pg_extern]
+fn piibytea_debug(input: PiiBytea) -> String {
+    privileges::require_admin("piibytea_debug");
+    let pii = PiiByteaContents::from(input.inner.as_slice());
+    format!("{:?}", pii)
+}
+
+// Encrypt binary data with specified key_id
+#[pg_extern(immutable, strict)]
+fn piibytea_encrypt(data: &[u8], key_id_bytes: Vec<u8>) -> PiiBytea {
+    let key = keys::resolve_key(&key_id_bytes).unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let context = contents::aad("piibytea", &key_id_bytes, None, None);
+    match crypto::encrypt_bytes(data, &key, &key_id_bytes, &context) {
+        Ok(sealed) => PiiBytea {
+            inner: PiiByteaContents::Sealed(sealed).into(),
+        },
+        Err(e) => {
+            pgrx::error!("Encryption failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use once_cell::sync::Lazy;
+use pgrx::prelude::*;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Declarative column policies: pii_vault.protect() converts a column to piitext,
+// installs pii_vault_encrypt_trigger for it, seals the existing rows and records
+// the policy; pii_vault.unprotect() reverses it. aad_relation/aad_attribute are
+// labels fixed when the policy is created, so they survive renames and dumps.
+extension_sql!(
+    r#"
+CREATE SCHEMA IF NOT EXISTS pii_vault;
+
+CREATE TABLE pii_vault.column_policy (
+    relid regclass NOT NULL,
+    column_name name NOT NULL,
+    subject_column name NOT NULL,
+    key_namespace text CHECK (key_namespace <> ''),
+    algorithm text NOT NULL DEFAULT 'aes-256-gcm' CHECK (algorithm IN ('aes-256-gcm', 'aes-256-gcm-siv')),
+    mask_rule text NOT NULL DEFAULT 'full',
+    aad_relation text,
+    aad_attribute text,
+    allowed_purposes text[] CHECK (array_position(allowed_purposes, NULL) IS NULL),
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (relid, column_name)
+);
+SELECT pg_catalog.pg_extension_config_dump('pii_vault.column_policy', '');
+-- Policies are not secret; piitext output reads their mask rules as the caller
+GRANT USAGE ON SCHEMA pii_vault TO PUBLIC;
+GRANT SELECT ON pii_vault.column_policy TO PUBLIC;
+
+CREATE FUNCTION pii_vault.protect(
+    rel regclass,
+    column_name name,
+    subject_column name,
+    key_namespace text DEFAULT NULL,
+    mask_rule text DEFAULT 'full',
+    aad_relation text DEFAULT NULL,
+    aad_attribute text DEFAULT NULL,
+    algorithm text DEFAULT 'aes-256-gcm',
+    allowed_purposes text[] DEFAULT NULL
+) RETURNS void
+LANGUAGE plpgsql
+SET search_path = pg_catalog, @extschema@
+AS $$
+DECLARE
+    column_type regtype;
+    trigger_name name := left('pii_vault_' || column_name, 63);
+    trigger_args text;
+BEGIN
+    SELECT atttypid::regtype INTO column_type
+    FROM pg_attribute
+    WHERE attrelid = rel AND attname = column_name AND attnum > 0 AND NOT attisdropped;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'column "%" of relation % does not exist', column_name, rel;
+    END IF;
+
+    PERFORM 1
+    FROM pg_attribute
+    WHERE attrelid = rel AND attname = subject_column AND attnum > 0 AND NOT attisdropped;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'subject column "%" of relation % does not exist', subject_column, rel;
+    END IF;
+
+    -- Deterministic sealing uses one key per namespace rather than per subject
+    IF algorithm = 'aes-256-gcm-siv' AND key_namespace IS NULL THEN
+        RAISE EXCEPTION 'algorithm aes-256-gcm-siv requires a key_namespace';
+    END IF;
+
+    -- Reject unknown mask rules now rather than when the column is read
+    PERFORM piitext_mask(piitext_in_text(''), mask_rule);
+
+    -- Values find their policy through their AAD labels; without labels a mask
+    -- rule or purpose restriction would never apply
+    IF aad_relation IS NULL AND aad_attribute IS NULL
+       AND (mask_rule <> 'full' OR allowed_purposes IS NOT NULL) THEN
+        RAISE EXCEPTION 'mask_rule and allowed_purposes require aad_relation or aad_attribute';
+    END IF;
+
+    IF column_type <> 'piitext'::regtype THEN
+        EXECUTE format('ALTER TABLE %s ALTER COLUMN %I TYPE piitext USING piitext_in_text(%I::text)',
+                       rel, column_name, column_name);
+    END IF;
+
+    INSERT INTO pii_vault.column_policy AS p
+        (relid, column_name, subject_column, key_namespace, algorithm, mask_rule, aad_relation, aad_attribute, allowed_purposes)
+    VALUES (rel, column_name, subject_column, key_namespace, algorithm, mask_rule, aad_relation, aad_attribute, allowed_purposes)
+    ON CONFLICT ON CONSTRAINT column_policy_pkey DO UPDATE
+        SET subject_column = EXCLUDED.subject_column,
+            key_namespace = EXCLUDED.key_namespace,
+            algorithm = EXCLUDED.algorithm,
+            mask_rule = EXCLUDED.mask_rule,
+            aad_relation = EXCLUDED.aad_relation,
+            aad_attribute = EXCLUDED.aad_attribute,
+            allowed_purposes = EXCLUDED.allowed_purposes;
+
+    trigger_args := format('%L, %L', subject_column, column_name);
+    IF key_namespace IS NOT NULL THEN
+        trigger_args := trigger_args || format(', %L', 'namespace=' || key_namespace);
+    END IF;
+    IF algorithm <> 'aes-256-gcm' THEN
+        trigger_args := trigger_args || format(', %L', 'algorithm=' || algorithm);
+    END IF;
+    IF aad_relation IS NOT NULL THEN
+        trigger_args := trigger_args || format(', %L', 'relation=' || aad_relation);
+    END IF;
+    IF aad_attribute IS NOT NULL THEN
+        trigger_args := trigger_args || format(', %L', 'attribute=' || aad_attribute);
+    END IF;
+
+    EXECUTE format('DROP TRIGGER IF EXISTS %I ON %s', trigger_name, rel);
+    EXECUTE format('CREATE TRIGGER %I BEFORE INSERT OR UPDATE ON %s FOR EACH ROW EXECUTE FUNCTION pii_vault_encrypt_trigger(%s)',
+                   trigger_name, rel, trigger_args);
+
+    -- Move sealed rows to the configured AAD bindings first, so the trigger
+    -- accepts them, then let it seal whatever is still stored in plaintext
+    PERFORM pii_vault.rebind(rel, column_name);
+    EXECUTE format('UPDATE %s SET %I = %I WHERE %I IS NOT NULL', rel, column_name, column_name, column_name);
+END;
+$$;
+
+-- Re-seal rows whose AAD bindings differ from the column policy, e.g. rows
+-- written before aad_relation/aad_attribute were configured
+CREATE FUNCTION pii_vault.rebind(rel regclass, column_name name) RETURNS bigint
+LANGUAGE plpgsql
+SET search_path = pg_catalog, @extschema@
+AS $$
+DECLARE
+    policy pii_vault.column_policy;
+    rebound bigint;
+BEGIN
+    SELECT * INTO policy
+    FROM pii_vault.column_policy p
+    WHERE p.relid = rel AND p.column_name = rebind.column_name;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'column "%" of relation % is not protected', column_name, rel;
+    END IF;
+
+    EXECUTE format('UPDATE %s SET %I = piitext_rebind(%I, $1, $2) WHERE %I IS NOT NULL AND NOT piitext_bound_to(%I, $1, $2)',
+                   rel, column_name, column_name, column_name, column_name)
+        USING policy.aad_relation, policy.aad_attribute;
+    GET DIAGNOSTICS rebound = ROW_COUNT;
+    RETURN rebound;
+END;
+$$;
+
+CREATE FUNCTION pii_vault.unprotect(
+    rel regclass,
+    column_name name,
+    decrypt boolean DEFAULT false
+) RETURNS void
+LANGUAGE plpgsql
+SET search_path = pg_catalog, @extschema@
+AS $$
+BEGIN
+    PERFORM FROM pii_vault.column_policy p
+    WHERE p.relid = rel AND p.column_name = unprotect.column_name
+    FOR UPDATE;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'column "%" of relation % is not protected', column_name, rel;
+    END IF;
+
+    EXECUTE format('DROP TRIGGER IF EXISTS %I ON %s', left('pii_vault_' || column_name, 63), rel);
+
+    -- Decrypted while the policy still applies, so its purposes are enforced
+    IF decrypt THEN
+        EXECUTE format('ALTER TABLE %s ALTER COLUMN %I TYPE text USING piitext_decrypt_or_error(%I)',
+                       rel, column_name, column_name);
+    END IF;
+
+    DELETE FROM pii_vault.column_policy p WHERE p.relid = rel AND p.column_name = unprotect.column_name;
+END;
+$$;
+"#,
+    name = "column_policy"
+);
+
+pub struct ColumnPolicy {
+    pub mask_rule: String,
+    // None allows any purpose, including none
+    pub allowed_purposes: Option<Vec<String>>,
+}
+
+impl ColumnPolicy {
+    pub fn allows(&self, purpose: Option<&str>) -> bool {
+        match (&self.allowed_purposes, purpose) {
+            (None, _) => true,
+            (Some(allowed), Some(purpose)) => allowed.iter().any(|p| p == purpose),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+type PolicyKey = (Option<String>, Option<String>);
+
+// Start time and command id of the statement the cached policies belong to
+type StatementId = (pg_sys::TimestampTz, pg_sys::CommandId);
+
+// Policies looked up during the current statement, so reading a column costs
+// one catalog query rather than one per row. The command id changes after every
+// write in the transaction, which includes changes to the policies themselves.
+static STATEMENT_POLICIES: Lazy<Mutex<PolicyCache>> =
+    Lazy::new(|| Mutex::new(PolicyCache::default()));
+
+#[derive(Default)]
+struct PolicyCache {
+    statement: StatementId,
+    policies: HashMap<PolicyKey, Option<Arc<ColumnPolicy>>>,
+}
+
+// Column policy whose AAD labels match a sealed value; unbound values belong to
+// no policy
+pub fn policy_for(relation: Option<&str>, attribute: Option<&str>) -> Option<Arc<ColumnPolicy>> {
+    if relation.is_none() && attribute.is_none() {
+        return None;
+    }
+
+    let statement = unsafe {
+        (
+            pg_sys::GetCurrentStatementStartTimestamp(),
+            pg_sys::GetCurrentCommandId(false),
+        )
+    };
+    let mut cache = STATEMENT_POLICIES.lock().unwrap_or_else(|e| e.into_inner());
+    if cache.statement != statement {
+        cache.statement = statement;
+        cache.policies.clear();
+    }
+
+    let key = (relation.map(str::to_owned), attribute.map(str::to_owned));
+    cache
+        .policies
+        .entry(key)
+        .or_insert_with(|| lookup_policy(relation, attribute).map(Arc::new))
+        .clone()
+}
+
+fn lookup_policy(relation: Option<&str>, attribute: Option<&str>) -> Option<ColumnPolicy> {
+    let (mask_rule, allowed_purposes) = Spi::get_two_with_args::<String, Vec<Option<String>>>(
+        "SELECT mask_rule, allowed_purposes FROM pii_vault.column_policy \
+         WHERE aad_relation IS NOT DISTINCT FROM $1 AND aad_attribute IS NOT DISTINCT FROM $2 \
+         ORDER BY created_at LIMIT 1",
+        &[relation.into(), attribute.into()],
+    )
+    .unwrap_or_else(|e| pgrx::error!("pii_vault: column policy lookup failed: {}", e));
+
+    Some(ColumnPolicy {
+        mask_rule: mask_rule?,
+        allowed_purposes: allowed_purposes.map(|purposes| purposes.into_iter().flatten().collect()),
+    })
+}
@@ -0,0 +1,93 @@
+// Role-based gating of decryption. pii_vault.reader_role names the role whose
+// members may see plaintext; everyone else gets the masked form of the column
+// policy (piitext) or NULL (piibytea and typed scalars). pii_vault.admin_role
+// names the role required for debug, raw and rotation functions. An unset role
+// disables the corresponding check.
+//
+// Membership is checked for the current user, so SECURITY DEFINER functions
+// such as pii_detokenize decrypt with the privileges of their owner.
+use crate::{PII_VAULT_ADMIN_ROLE, PII_VAULT_PURPOSE, PII_VAULT_READER_ROLE};
+use pgrx::guc::GucSetting;
+use pgrx::prelude::*;
+use std::ffi::{CStr, CString};
+
+// None when no role is configured, otherwise whether the current user has the
+// privileges of the role
+fn member_of(setting: &GucSetting<Option<CString>>) -> Option<bool> {
+    let role = setting.get()?;
+    if role.as_bytes().is_empty() {
+        return None;
+    }
+
+    Some(has_privs_of(&role))
+}
+
+// Whether the current user has the privileges of role (a role that does not
+// exist has no members)
+pub fn has_privs_of(role: &CStr) -> bool {
+    unsafe {
+        let role_oid = pg_sys::get_role_oid(role.as_ptr(), true);
+        role_oid != pg_sys::InvalidOid && pg_sys::has_privs_of_role(pg_sys::GetUserId(), role_oid)
+    }
+}
+
+// pii_vault.purpose, None when unset or empty
+pub fn current_purpose() -> Option<String> {
+    let purpose = PII_VAULT_PURPOSE.get()?;
+    let purpose = purpose.to_string_lossy().trim().to_string();
+    (!purpose.is_empty()).then_some(purpose)
+}
+
+pub fn is_reader() -> bool {
+    member_of(&PII_VAULT_READER_ROLE).unwrap_or(true)
+}
+
+// For functions that can only return plaintext, so there is nothing to mask
+pub fn require_reader(function: &str) {
+    require(&PII_VAULT_READER_ROLE, function);
+}
+
+pub fn require_admin(function: &str) {
+    require(&PII_VAULT_ADMIN_ROLE, function);
+}
+
+fn require(setting: &GucSetting<Option<CString>>, function: &str) {
+    if member_of(setting) == Some(false) {
+        let role = setting.get().unwrap_or_default();
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+            format!(
+                "permission denied for function {}: requires membership in role \"{}\"",
+                function,
+                role.to_string_lossy()
+            )
+        );
+    }
+}
+
+// Run f with the privileges of the owner of relation, for extension tables that
+// record what the calling roles do (audit log, shredding ledger) but that they
+// may not write themselves. An error inside f aborts the transaction, which
+// restores the user id.
+pub fn as_owner_of<R>(relation: &str, f: impl FnOnce() -> R) -> R {
+    let owner = Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT relowner FROM pg_catalog.pg_class WHERE oid = $1::regclass",
+        &[relation.into()],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or_else(|| pgrx::error!("pii_vault: relation {} is missing", relation));
+
+    let (mut saved_user, mut saved_context) = (pg_sys::InvalidOid, 0);
+    unsafe {
+        pg_sys::GetUserIdAndSecContext(&mut saved_user, &mut saved_context);
+        pg_sys::SetUserIdAndSecContext(
+            owner,
+            saved_context | pg_sys::SECURITY_LOCAL_USERID_CHANGE as i32,
+        );
+    }
+    let result = f();
+    unsafe { pg_sys::SetUserIdAndSecContext(saved_user, saved_context) };
+    result
+}
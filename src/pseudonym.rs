@@ -0,0 +1,185 @@
+// Pseudonyms for analytics: an HMAC-SHA256 of the subject's key_id under the
+// Vault key of a project. Stable within a project, unlinkable across projects,
+// and unlinkable to the old ones once the project key is shredded. Each project
+// is registered in pii_vault.pseudonym_project with the role that may compute
+// its pseudonyms: anyone else could compute the pseudonym of every key_id and
+// so re-identify subjects or link them across projects.
+use crate::keys::{self, KeyKind};
+use crate::stats::Eviction;
+use crate::{cache, privileges, vault};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use pgrx::prelude::*;
+use pgrx::{register_subxact_callback, register_xact_callback};
+use pgrx::{PgSubXactCallbackEvent, PgXactCallbackEvent};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Start time and command id of the statement the cached project roles belong to
+type StatementId = (pg_sys::TimestampTz, pg_sys::CommandId);
+
+// Roles of the projects looked up during the current statement, so that
+// pseudonymizing a table costs one catalog query per project rather than per row
+static STATEMENT_ROLES: Lazy<Mutex<ProjectRoles>> =
+    Lazy::new(|| Mutex::new(ProjectRoles::default()));
+
+#[derive(Default)]
+struct ProjectRoles {
+    statement: StatementId,
+    roles: HashMap<String, Option<CString>>,
+}
+
+// Projects shredded in the current transaction, with the subtransaction that
+// shredded them; their keys are deleted from Vault at commit
+static SHREDDED: Lazy<Mutex<HashMap<String, pg_sys::SubTransactionId>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Raise an error unless the current user has the privileges of the role that
+// project is registered with
+fn require_project_role(project: &str) {
+    let statement = unsafe {
+        (
+            pg_sys::GetCurrentStatementStartTimestamp(),
+            pg_sys::GetCurrentCommandId(false),
+        )
+    };
+    let mut roles = STATEMENT_ROLES.lock().unwrap_or_else(|e| e.into_inner());
+    if roles.statement != statement {
+        roles.statement = statement;
+        roles.roles.clear();
+    }
+
+    let role = roles.roles.entry(project.to_owned()).or_insert_with(|| {
+        Spi::get_one_with_args::<String>(
+            "SELECT role::text FROM pii_vault.pseudonym_project WHERE project = $1",
+            &[project.into()],
+        )
+        .unwrap_or_else(|e| pgrx::error!("pii_vault: pseudonym project lookup failed: {}", e))
+        .and_then(|role| CString::new(role).ok())
+    });
+    match role {
+        None => pgrx::error!(
+            "pii_pseudonym: project \"{}\" is not registered in pii_vault.pseudonym_project",
+            project
+        ),
+        Some(role) if !privileges::has_privs_of(role) => {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+                format!(
+                    "permission denied for pseudonym project \"{}\": requires membership in role \"{}\"",
+                    project,
+                    role.to_string_lossy()
+                )
+            );
+        }
+        Some(_) => {}
+    }
+}
+
+// Pseudonym of subject in project, as hex (128 bits), uuid (version 8) or a
+// non-negative bigint rendered as text (63 bits)
+#[pg_extern(stable, strict, parallel_safe)]
+fn pii_pseudonym(subject: &[u8], project: &str, format: default!(&str, "'hex'")) -> String {
+    require_project_role(project);
+    let key = keys::resolve(KeyKind::Pseudonym, project.as_bytes()).unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let mut mac = HmacSha256::new_from_slice(&key[..]).expect("HMAC accepts any key length");
+    mac.update(subject);
+    let digest = mac.finalize().into_bytes();
+
+    match format {
+        "hex" => hex::encode(&digest[..16]),
+        "uuid" => {
+            let mut bytes = [0u8; 16];
+            bytes.copy_from_slice(&digest[..16]);
+            // RFC 9562 version 8 (custom) and variant bits
+            bytes[6] = (bytes[6] & 0x0f) | 0x80;
+            bytes[8] = (bytes[8] & 0x3f) | 0x80;
+            format!(
+                "{}-{}-{}-{}-{}",
+                hex::encode(&bytes[..4]),
+                hex::encode(&bytes[4..6]),
+                hex::encode(&bytes[6..8]),
+                hex::encode(&bytes[8..10]),
+                hex::encode(&bytes[10..])
+            )
+        }
+        "integer" => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&digest[..8]);
+            (u64::from_be_bytes(bytes) >> 1).to_string()
+        }
+        _ => pgrx::error!(
+            "pii_pseudonym: unknown format \"{}\", expected hex, uuid or integer",
+            format
+        ),
+    }
+}
+
+// Delete the Vault key of project when the transaction commits: pseudonyms
+// computed afterwards use a new key and cannot be linked to the old ones. The
+// key is dropped from the key cache of every session right away.
+#[pg_extern(volatile, strict)]
+fn pii_pseudonym_shred(project: &str) {
+    privileges::require_admin("pii_pseudonym_shred");
+
+    let mut shredded = SHREDDED.lock().unwrap_or_else(|e| e.into_inner());
+    if shredded.is_empty() {
+        register_xact_callback(PgXactCallbackEvent::PreCommit, delete_shredded);
+        register_xact_callback(PgXactCallbackEvent::Abort, || {
+            SHREDDED.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        });
+        // Subtransactions started later are still running, so the aborted one
+        // and its children are those with an id from my_subid on
+        register_subxact_callback(PgSubXactCallbackEvent::AbortSub, |my_subid, _| {
+            SHREDDED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .retain(|_, subid| *subid < my_subid);
+        });
+    }
+    shredded
+        .entry(project.to_owned())
+        .or_insert_with(|| unsafe { pg_sys::GetCurrentSubTransactionId() });
+    drop(shredded);
+
+    cache::evict(KeyKind::Pseudonym, project.as_bytes(), Eviction::Shredded);
+}
+
+fn delete_shredded() {
+    let shredded = std::mem::take(&mut *SHREDDED.lock().unwrap_or_else(|e| e.into_inner()));
+    for project in shredded.into_keys() {
+        if !keys::is_mock() {
+            vault::delete_key(&KeyKind::Pseudonym.vault_key_name(project.as_bytes()))
+                .unwrap_or_else(|e| {
+                    pgrx::error!("Vault error: {}", e);
+                });
+        }
+        // The key may have been fetched again later in the transaction
+        cache::evict(KeyKind::Pseudonym, project.as_bytes(), Eviction::Shredded);
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE pii_vault.pseudonym_project (
+    project text PRIMARY KEY,
+    role name NOT NULL
+);
+REVOKE ALL ON pii_vault.pseudonym_project FROM PUBLIC;
+GRANT SELECT ON pii_vault.pseudonym_project TO PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('pii_vault.pseudonym_project', '');
+
+REVOKE EXECUTE ON FUNCTION pii_pseudonym(bytea, text, text) FROM PUBLIC;
+REVOKE EXECUTE ON FUNCTION pii_pseudonym_shred(text) FROM PUBLIC;
+"#,
+    name = "pseudonym_project",
+    requires = ["column_policy", pii_pseudonym, pii_pseudonym_shred]
+);
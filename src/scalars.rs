@@ -0,0 +1,142 @@
+// Typed PII scalars: the plaintext is the value's canonical binary form sealed in
+// the same PiiSealedData envelope as piitext, so no staging state exists here.
+use crate::contents::{self, PiiSealedData};
+use crate::stats::{self, Failure};
+use crate::{audit, crypto, keys, privileges};
+use pgrx::datum::{AnyNumeric, Date, FromDatum, IntoDatum};
+use pgrx::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+pub struct PiiDate {
+    inner: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+pub struct PiiNumeric {
+    inner: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PostgresType)]
+pub struct PiiInt8 {
+    inner: Vec<u8>,
+}
+
+fn seal(type_name: &str, plaintext: &[u8], key_id_bytes: &[u8]) -> Vec<u8> {
+    let key = keys::resolve_key(key_id_bytes).unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let context = contents::aad(type_name, key_id_bytes, None, None);
+    match crypto::encrypt_bytes(plaintext, &key, key_id_bytes, &context) {
+        Ok(sealed) => serde_cbor::to_vec(&sealed).expect("CBOR serialization failed"),
+        Err(e) => {
+            pgrx::error!("Encryption failed: {}", e);
+        }
+    }
+}
+
+// None when the envelope is unreadable, the key has been shredded or the
+// current user is not a reader
+fn open(type_name: &str, inner: &[u8]) -> Option<Vec<u8>> {
+    if !privileges::is_reader() {
+        return None;
+    }
+    let sealed: PiiSealedData = match serde_cbor::from_slice(inner) {
+        Ok(sealed) => sealed,
+        Err(_) => {
+            stats::count_failure(Failure::Malformed);
+            return None;
+        }
+    };
+    let context = sealed.aad(type_name);
+    let plaintext = keys::resolve_sealed(&sealed)
+        .ok()
+        .and_then(|key| crypto::decrypt_bytes(&sealed, &key, &context).ok());
+    match plaintext {
+        Some(_) => audit::record(&sealed, audit::Outcome::Plaintext),
+        None => audit::record(&sealed, audit::Outcome::Failed),
+    }
+    plaintext
+}
+
+fn debug(function: &str, inner: &[u8]) -> String {
+    privileges::require_admin(function);
+    match serde_cbor::from_slice::<PiiSealedData>(inner) {
+        Ok(sealed) => format!("Sealed({:?})", sealed),
+        Err(e) => format!("Invalid({})", e),
+    }
+}
+
+// date is sealed as its DateADT (days since 2000-01-01, big-endian), which keeps
+// infinity and is independent of DateStyle
+#[pg_extern(immutable, strict)]
+fn piidate_encrypt(value: Date, key_id_bytes: Vec<u8>) -> PiiDate {
+    let days = value.into_datum().expect("date datum").value() as i32;
+    PiiDate {
+        inner: seal("piidate", &days.to_be_bytes(), &key_id_bytes),
+    }
+}
+
+#[pg_extern(stable, strict, name = "piidate_out_date")]
+fn piidate_output(input: PiiDate) -> Option<Date> {
+    let bytes: [u8; 4] = open("piidate", &input.inner)?.try_into().ok()?;
+    let days = i32::from_be_bytes(bytes);
+    unsafe { Date::from_datum(pg_sys::Datum::from(days), false) }
+}
+
+#[pg_extern]
+fn piidate_debug(input: PiiDate) -> String {
+    debug("piidate_debug", &input.inner)
+}
+
+// numeric is sealed as its canonical text form, which numeric_out produces
+// without regard to locale
+#[pg_extern(immutable, strict)]
+fn piinumeric_encrypt(value: AnyNumeric, key_id_bytes: Vec<u8>) -> PiiNumeric {
+    PiiNumeric {
+        inner: seal("piinumeric", value.to_string().as_bytes(), &key_id_bytes),
+    }
+}
+
+#[pg_extern(stable, strict, name = "piinumeric_out_numeric")]
+fn piinumeric_output(input: PiiNumeric) -> Option<AnyNumeric> {
+    let text = String::from_utf8(open("piinumeric", &input.inner)?).ok()?;
+    AnyNumeric::from_str(&text).ok()
+}
+
+#[pg_extern]
+fn piinumeric_debug(input: PiiNumeric) -> String {
+    debug("piinumeric_debug", &input.inner)
+}
+
+// int8 is sealed as 8 big-endian bytes
+#[pg_extern(immutable, strict)]
+fn piiint8_encrypt(value: i64, key_id_bytes: Vec<u8>) -> PiiInt8 {
+    PiiInt8 {
+        inner: seal("piiint8", &value.to_be_bytes(), &key_id_bytes),
+    }
+}
+
+#[pg_extern(stable, strict, name = "piiint8_out_int8")]
+fn piiint8_output(input: PiiInt8) -> Option<i64> {
+    let bytes: [u8; 8] = open("piiint8", &input.inner)?.try_into().ok()?;
+    Some(i64::from_be_bytes(bytes))
+}
+
+#[pg_extern]
+fn piiint8_debug(input: PiiInt8) -> String {
+    debug("piiint8_debug", &input.inner)
+}
+
+// Decrypt implicitly so e.g. `current_date - date_of_birth` works as on a plain date
+extension_sql!(
+    r#"
+CREATE CAST (piidate AS date) WITH FUNCTION piidate_out_date(piidate) AS IMPLICIT;
+CREATE CAST (piinumeric AS numeric) WITH FUNCTION piinumeric_out_numeric(piinumeric) AS IMPLICIT;
+CREATE CAST (piiint8 AS int8) WITH FUNCTION piiint8_out_int8(piiint8) AS IMPLICIT;
+"#,
+    name = "pii_scalar_casts",
+    requires = [piidate_output, piinumeric_output, piiint8_output]
+);
@@ -0,0 +1,126 @@
+// Protected memory for key material. Keys live in 32-byte cells carved out of
+// pages that are locked in RAM (mlock: never written to swap) and excluded from
+// core dumps (MADV_DONTDUMP), and are zeroized when dropped. Pages are kept for
+// the life of the backend and their cells reused.
+//
+// If the backend may not lock more memory (RLIMIT_MEMLOCK), keys are still
+// excluded from core dumps and zeroized, and a warning is logged once.
+use once_cell::sync::Lazy;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use zeroize::Zeroize;
+
+const KEY_LEN: usize = 32;
+
+struct Pool {
+    free: Vec<NonNull<[u8; KEY_LEN]>>,
+}
+
+// The cells are only reached through the pool lock or their owning SecureKey
+unsafe impl Send for Pool {}
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool { free: Vec::new() }));
+static LOCK_WARNED: AtomicBool = AtomicBool::new(false);
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+// Lock [ptr, ptr + len) in RAM and keep it out of core dumps; the range is
+// widened to whole pages. Returns false if it could not be locked.
+pub fn protect(ptr: *const u8, len: usize) -> bool {
+    let page = page_size();
+    let start = ptr as usize & !(page - 1);
+    let end = (ptr as usize + len + page - 1) & !(page - 1);
+    unsafe {
+        #[cfg(target_os = "linux")]
+        libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_DONTDUMP);
+        libc::mlock(start as *const libc::c_void, end - start) == 0
+    }
+}
+
+pub fn warn_unlocked() {
+    if !LOCK_WARNED.swap(true, Ordering::Relaxed) {
+        pgrx::warning!(
+            "pii_vault: could not lock key memory ({}), keys may be swapped out; raise RLIMIT_MEMLOCK (LimitMEMLOCK) for PostgreSQL",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+fn allocate() -> NonNull<[u8; KEY_LEN]> {
+    let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+    if pool.free.is_empty() {
+        let page = page_size();
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            pgrx::error!("pii_vault: out of memory for key material");
+        }
+        if !protect(ptr as *const u8, page) {
+            warn_unlocked();
+        }
+        let cells = ptr as *mut [u8; KEY_LEN];
+        for i in 0..page / KEY_LEN {
+            pool.free
+                .push(NonNull::new(unsafe { cells.add(i) }).expect("mmap returned a valid page"));
+        }
+    }
+    pool.free.pop().expect("pool has free cells")
+}
+
+// A 32-byte key in protected memory
+pub struct SecureKey {
+    cell: NonNull<[u8; KEY_LEN]>,
+}
+
+unsafe impl Send for SecureKey {}
+unsafe impl Sync for SecureKey {}
+
+impl SecureKey {
+    // Copies bytes into a protected cell; the caller wipes its own copy
+    pub fn from_slice(bytes: &[u8]) -> Option<SecureKey> {
+        if bytes.len() != KEY_LEN {
+            return None;
+        }
+        let cell = allocate();
+        unsafe { (*cell.as_ptr()).copy_from_slice(bytes) };
+        Some(SecureKey { cell })
+    }
+
+    // The all-zero key of mock mode
+    pub fn zeroed() -> SecureKey {
+        SecureKey::from_slice(&[0u8; KEY_LEN]).expect("32 bytes")
+    }
+}
+
+impl Deref for SecureKey {
+    type Target = [u8; KEY_LEN];
+
+    fn deref(&self) -> &[u8; KEY_LEN] {
+        unsafe { self.cell.as_ref() }
+    }
+}
+
+impl Drop for SecureKey {
+    fn drop(&mut self) {
+        unsafe { self.cell.as_mut() }.zeroize();
+        POOL.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .free
+            .push(self.cell);
+    }
+}
@@ -0,0 +1,394 @@
+// Key cache in shared memory, used when pg_pii_vault is in
+// shared_preload_libraries: a key fetched by one backend serves every other
+// backend until it expires. The table is set-associative (a key_id hashes to a
+// bucket of WAYS slots), so lookups scan one bucket and never grow memory.
+// Lookups hold the table lock in shared mode, so backends hitting the cache do
+// not wait for each other; they mark the entries they use with atomics. Inserts,
+// evictions and the worker lock it exclusively.
+//
+// The "pii_vault key cache" background worker sweeps expired entries, trims the
+// table to pii_vault.cache_max_entries and refreshes keys that were used since
+// they were fetched shortly before they expire, so foreground queries on hot
+// keys do not wait for Vault. Refreshing uses the Vault settings of the server
+// configuration; without pii_vault.url there, the worker only sweeps.
+//
+// Every entry records the provider (pii_vault.url, pii_vault.mount and
+// pii_vault.token) its key came from, and is only served to sessions using the same provider; the
+// worker refreshes only keys of the provider in the server configuration.
+//
+// The shared memory also holds the cache generation, which every flush and
+// eviction advances: backends drop their own caches when they see it change,
+// so a revoked key leaves every backend at once.
+//
+// Every process that uses the table locks it in RAM and excludes it from core
+// dumps, as for the backend's own key memory (see secure.rs).
+use crate::keys;
+use crate::keys::KeyKind;
+use crate::secure::{self, SecureKey};
+use crate::stats::{self, Counter, Eviction};
+use crate::vault::{self, Provider};
+use crate::{PII_VAULT_CACHE_MAX_ENTRIES, PII_VAULT_CACHE_PREFETCH_SEC, PII_VAULT_CACHE_TTL};
+use pgrx::atomics::*;
+use pgrx::bgworkers::*;
+use pgrx::lwlock::{PgLwLock, PgLwLockExclusiveGuard, PgLwLockShareGuard};
+use pgrx::pg_shmem_init;
+use pgrx::prelude::*;
+use pgrx::shmem::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::Zeroize;
+
+const WAYS: usize = 8;
+const BUCKETS: usize = 512;
+pub const CAPACITY: usize = WAYS * BUCKETS;
+// Longer key_ids (long text subject ids, namespaces) stay in the backend cache
+pub const MAX_KEY_ID: usize = 64;
+
+struct Slot {
+    used: bool,
+    provider: Provider,
+    kind: u8,
+    key_id_len: u8,
+    key_id: [u8; MAX_KEY_ID],
+    key: [u8; 32],
+    fetched_at_ms: i64,
+    expires_at_ms: i64,
+    // Lookups only hold the shared lock and mark use through these
+    last_used_ms: AtomicI64,
+    // Used since it was fetched, so worth refreshing before it expires
+    touched: AtomicBool,
+}
+
+impl Slot {
+    fn empty() -> Slot {
+        Slot {
+            used: false,
+            provider: [0; 16],
+            kind: 0,
+            key_id_len: 0,
+            key_id: [0; MAX_KEY_ID],
+            key: [0; 32],
+            fetched_at_ms: 0,
+            expires_at_ms: 0,
+            last_used_ms: AtomicI64::new(0),
+            touched: AtomicBool::new(false),
+        }
+    }
+
+    // Whether the slot caches key_id, whichever provider it came from
+    fn holds(&self, kind: KeyKind, key_id: &[u8]) -> bool {
+        self.used && self.kind == kind as u8 && &self.key_id[..self.key_id_len as usize] == key_id
+    }
+
+    fn key_kind(&self) -> KeyKind {
+        KeyKind::ALL[self.kind as usize]
+    }
+}
+
+pub struct Table {
+    slots: [Slot; CAPACITY],
+    entries: usize,
+}
+
+unsafe impl PGRXSharedMemory for Table {}
+
+impl Default for Table {
+    fn default() -> Self {
+        Table {
+            slots: std::array::from_fn(|_| Slot::empty()),
+            entries: 0,
+        }
+    }
+}
+
+impl Table {
+    // The same key_id of all providers shares a bucket, so it can be evicted at once
+    fn bucket(kind: KeyKind, key_id: &[u8]) -> std::ops::Range<usize> {
+        let mut hasher = DefaultHasher::new();
+        (kind as u8).hash(&mut hasher);
+        key_id.hash(&mut hasher);
+        let start = (hasher.finish() as usize % BUCKETS) * WAYS;
+        start..start + WAYS
+    }
+
+    fn find(&self, provider: &Provider, kind: KeyKind, key_id: &[u8]) -> Option<usize> {
+        Self::bucket(kind, key_id)
+            .find(|&i| self.slots[i].holds(kind, key_id) && &self.slots[i].provider == provider)
+    }
+
+    fn clear(&mut self, i: usize, reason: Eviction) {
+        if self.slots[i].used {
+            self.slots[i].key.zeroize();
+            self.slots[i] = Slot::empty();
+            self.entries -= 1;
+            stats::count_eviction(reason);
+        }
+    }
+}
+
+static TABLE: PgLwLock<Table> = unsafe { PgLwLock::new(c"pii_vault_key_cache") };
+static GENERATION: PgAtomic<AtomicU64> = unsafe { PgAtomic::new(c"pii_vault_cache_generation") };
+static READY: AtomicBool = AtomicBool::new(false);
+static PROTECTED: Once = Once::new();
+
+fn table() -> PgLwLockExclusiveGuard<'static, Table> {
+    let table = TABLE.exclusive();
+    protect(&table);
+    table
+}
+
+// For lookups, which run concurrently
+fn shared_table() -> PgLwLockShareGuard<'static, Table> {
+    let table = TABLE.share();
+    protect(&table);
+    table
+}
+
+fn protect(table: &Table) {
+    PROTECTED.call_once(|| {
+        if !secure::protect(
+            table as *const Table as *const u8,
+            std::mem::size_of::<Table>(),
+        ) {
+            secure::warn_unlocked();
+        }
+    });
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+fn max_entries() -> usize {
+    (PII_VAULT_CACHE_MAX_ENTRIES.get().max(0) as usize).min(CAPACITY)
+}
+
+// Called from _PG_init; shared memory and workers can only be requested while
+// preloading
+pub fn init() {
+    if !unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        return;
+    }
+    pg_shmem_init!(TABLE);
+    pg_shmem_init!(GENERATION);
+    READY.store(true, Ordering::Relaxed);
+
+    BackgroundWorkerBuilder::new("pii_vault key cache")
+        .set_type("pii_vault key cache")
+        .set_library("pg_pii_vault")
+        .set_function("pii_vault_cache_worker_main")
+        .enable_shmem_access(None)
+        .set_start_time(BgWorkerStartTime::ConsistentState)
+        .set_restart_time(Some(Duration::from_secs(10)))
+        .load();
+}
+
+// Whether key_id is cached here rather than in the backend
+pub fn covers(key_id: &[u8]) -> bool {
+    READY.load(Ordering::Relaxed) && key_id.len() <= MAX_KEY_ID
+}
+
+pub fn get(provider: &Provider, kind: KeyKind, key_id: &[u8]) -> Option<Arc<SecureKey>> {
+    let now = now_ms();
+    let table = shared_table();
+    let i = table.find(provider, kind, key_id)?;
+    let slot = &table.slots[i];
+    if slot.expires_at_ms <= now {
+        return None;
+    }
+    slot.last_used_ms.store(now, Ordering::Relaxed);
+    slot.touched.store(true, Ordering::Relaxed);
+    SecureKey::from_slice(&slot.key).map(Arc::new)
+}
+
+pub fn insert(provider: &Provider, kind: KeyKind, key_id: &[u8], key: &SecureKey, ttl_secs: u64) {
+    let now = now_ms();
+    let max = max_entries();
+    let mut table = table();
+
+    let bucket = Table::bucket(kind, key_id);
+    let i = match table.find(provider, kind, key_id) {
+        Some(i) => {
+            table.clear(i, Eviction::Expired);
+            i
+        }
+        None => {
+            let expired = bucket
+                .clone()
+                .find(|&i| table.slots[i].used && table.slots[i].expires_at_ms <= now);
+            let empty = bucket.clone().find(|&i| !table.slots[i].used);
+            match (expired, empty) {
+                (Some(i), _) => {
+                    table.clear(i, Eviction::Expired);
+                    i
+                }
+                (None, Some(i)) if table.entries < max => i,
+                _ => match bucket
+                    .filter(|&i| table.slots[i].used)
+                    .min_by_key(|&i| table.slots[i].last_used_ms.load(Ordering::Relaxed))
+                {
+                    Some(i) => {
+                        table.clear(i, Eviction::Capacity);
+                        i
+                    }
+                    // Limit reached and nothing in this bucket to give up
+                    None => return,
+                },
+            }
+        }
+    };
+
+    // Written in place, so the key is not copied through the stack
+    let slot = &mut table.slots[i];
+    *slot = Slot {
+        used: true,
+        provider: *provider,
+        kind: kind as u8,
+        key_id_len: key_id.len() as u8,
+        fetched_at_ms: now,
+        expires_at_ms: now + (ttl_secs as i64).saturating_mul(1000),
+        last_used_ms: AtomicI64::new(now),
+        ..Slot::empty()
+    };
+    slot.key_id[..key_id.len()].copy_from_slice(key_id);
+    slot.key.copy_from_slice(&key[..]);
+    table.entries += 1;
+}
+
+// Drop key_id of every provider
+pub fn evict(kind: KeyKind, key_id: &[u8], reason: Eviction) -> bool {
+    let mut table = table();
+    let mut evicted = false;
+    for i in Table::bucket(kind, key_id) {
+        if table.slots[i].holds(kind, key_id) {
+            table.clear(i, reason);
+            evicted = true;
+        }
+    }
+    evicted
+}
+
+// Drop every key; returns how many there were
+pub fn flush() -> usize {
+    let mut table = table();
+    let entries = table.entries;
+    for i in 0..CAPACITY {
+        table.clear(i, Eviction::Flushed);
+    }
+    entries
+}
+
+// Number of entries and when the oldest of them was fetched (Unix ms)
+pub fn info() -> (usize, Option<i64>) {
+    let table = table();
+    let oldest = table
+        .slots
+        .iter()
+        .filter(|s| s.used)
+        .map(|s| s.fetched_at_ms)
+        .min();
+    (table.entries, oldest)
+}
+
+// None without shared memory
+pub fn generation() -> Option<u64> {
+    READY
+        .load(Ordering::Relaxed)
+        .then(|| GENERATION.get().load(Ordering::Acquire))
+}
+
+// Make every backend drop its own cache before its next lookup
+pub fn invalidate_backends() {
+    if READY.load(Ordering::Relaxed) {
+        GENERATION.get().fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// Drop expired entries, trim to the limit (least recently used first) and
+// return the hot keys of provider due for a refresh
+fn sweep(provider: Option<Provider>, prefetch_ms: i64) -> Vec<(Provider, KeyKind, Vec<u8>)> {
+    let now = now_ms();
+    let max = max_entries();
+    let mut table = table();
+
+    for i in 0..CAPACITY {
+        if table.slots[i].used && table.slots[i].expires_at_ms <= now {
+            table.clear(i, Eviction::Expired);
+        }
+    }
+
+    if table.entries > max {
+        let mut used: Vec<usize> = (0..CAPACITY).filter(|&i| table.slots[i].used).collect();
+        used.sort_by_key(|&i| table.slots[i].last_used_ms.load(Ordering::Relaxed));
+        let excess = table.entries - max;
+        for i in used.into_iter().take(excess) {
+            table.clear(i, Eviction::Capacity);
+        }
+    }
+
+    let Some(provider) = provider.filter(|_| prefetch_ms > 0) else {
+        return Vec::new();
+    };
+    table
+        .slots
+        .iter()
+        .filter(|s| s.used && s.provider == provider && s.touched.load(Ordering::Relaxed))
+        .filter(|s| s.expires_at_ms - now <= prefetch_ms)
+        .map(|s| {
+            let key_id = s.key_id[..s.key_id_len as usize].to_vec();
+            (provider, s.key_kind(), key_id)
+        })
+        .collect()
+}
+
+// Fetch the key again outside the lock; a key gone from Vault is dropped
+fn refresh(provider: &Provider, kind: KeyKind, key_id: &[u8], ttl_secs: u64) {
+    let fetched = vault::export_existing_key(&kind.vault_key_name(key_id), kind.export_type());
+
+    let mut table = table();
+    let Some(i) = table.find(provider, kind, key_id) else {
+        return;
+    };
+    match fetched {
+        Ok(Some(key)) => {
+            let now = now_ms();
+            let slot = &mut table.slots[i];
+            slot.key.copy_from_slice(&key[..]);
+            slot.fetched_at_ms = now;
+            slot.expires_at_ms = now + (ttl_secs as i64).saturating_mul(1000);
+            slot.touched.store(false, Ordering::Relaxed);
+            stats::count(Counter::CacheRefresh);
+        }
+        Ok(None) => table.clear(i, Eviction::Shredded),
+        // Left to expire; the next foreground use fetches it
+        Err(e) => pgrx::debug1!("pii_vault: refreshing cached key failed: {}", e),
+    }
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C-unwind" fn pii_vault_cache_worker_main(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    while BackgroundWorker::wait_latch(Some(Duration::from_secs(1))) {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext::PGC_SIGHUP) };
+        }
+
+        // Mock keys are never cached, and without a Vault there is nothing to refresh
+        let prefetch_ms = match !keys::is_mock() && vault::configured() {
+            true => PII_VAULT_CACHE_PREFETCH_SEC.get() as i64 * 1000,
+            false => 0,
+        };
+        let ttl_secs = PII_VAULT_CACHE_TTL.get() as u64;
+        for (provider, kind, key_id) in sweep(vault::provider(), prefetch_ms) {
+            refresh(&provider, kind, &key_id, ttl_secs);
+        }
+    }
+}
@@ -0,0 +1,325 @@
+// Crypto shredding and its ledger. pii_shred() deletes a subject's data key
+// from Vault and records the erasure in pii_vault.shred_ledger; keys found
+// missing from Vault while decrypting (deleted outside the extension) are
+// recorded as well, when they are missing from the Vault of the server
+// configuration. Every row names the Vault it refers to. Ledger rows are append-only and hash-chained: every row
+// stores the SHA-256 of the previous row's hash and its own contents, so
+// pii_vault.verify_shred_ledger() detects rows that were changed, removed or
+// inserted after the fact.
+use crate::keys::{self, KeyKind};
+use crate::stats::Eviction;
+use crate::{cache, privileges, vault};
+use once_cell::sync::Lazy;
+use pgrx::prelude::*;
+use pgrx::{register_xact_callback, PgXactCallbackEvent};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+// Missing keys noticed in the current transaction with the Vault that reported
+// them (None when it is not the server's), and those already written to the
+// ledger by this backend
+static MISSING: Lazy<Mutex<HashMap<Vec<u8>, Option<String>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static REPORTED: Lazy<Mutex<HashSet<Vec<u8>>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Keys shredded in the current transaction by the id of their ledger entry;
+// they are deleted from Vault at commit
+static SHREDDED: Lazy<Mutex<HashMap<i64, Vec<u8>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Vault answered 404 for a key that sealed a stored value. Recorded at commit,
+// since the reading transaction may still be read-only by then.
+pub fn key_missing(kind: KeyKind, key_id: &[u8]) {
+    // Namespace keys are not subject keys; losing one is not a subject erasure
+    if kind != KeyKind::Data {
+        return;
+    }
+    if REPORTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .contains(key_id)
+    {
+        return;
+    }
+
+    let mut missing = MISSING.lock().unwrap_or_else(|e| e.into_inner());
+    if missing.is_empty() {
+        register_xact_callback(PgXactCallbackEvent::PreCommit, record_missing);
+        register_xact_callback(PgXactCallbackEvent::Abort, || {
+            MISSING.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        });
+    }
+    if !missing.contains_key(key_id) {
+        let vault = server_vault().then(vault::location).flatten();
+        missing.insert(key_id.to_vec(), vault);
+    }
+}
+
+// Whether pii_vault.url and pii_vault.mount come from the server configuration.
+// Any role can point the session at a server of its own that answers 404 for
+// every key; only the configured Vault is evidence of an erasure.
+fn server_vault() -> bool {
+    Spi::get_one::<bool>(
+        "SELECT bool_and(source IN ('default', 'configuration file', 'command line', 'environment variable')) \
+         FROM pg_catalog.pg_settings WHERE name IN ('pii_vault.url', 'pii_vault.mount')",
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
+}
+
+fn record_missing() {
+    let (missing, unverified): (Vec<_>, Vec<_>) = MISSING
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .drain()
+        .partition(|(_, vault)| vault.is_some());
+    for (key_id, _) in &unverified {
+        pgrx::warning!(
+            "pii_vault: key {} is missing from Vault, not recorded in the shredding ledger since the session sets pii_vault.url or pii_vault.mount",
+            hex::encode(key_id)
+        );
+    }
+    if missing.is_empty() {
+        return;
+    }
+
+    if unsafe { pg_sys::XactReadOnly || pg_sys::RecoveryInProgress() } {
+        for (key_id, _) in &missing {
+            pgrx::warning!(
+                "pii_vault: key {} is missing from Vault, not recorded in the read-only shredding ledger",
+                hex::encode(key_id)
+            );
+        }
+        return;
+    }
+
+    privileges::as_owner_of("pii_vault.shred_ledger", || {
+        for (key_id, vault) in &missing {
+            Spi::run_with_args(
+                "INSERT INTO pii_vault.shred_ledger (key_id, vault_key, vault, source, performed_by) \
+                 SELECT $1, $2, $3, 'vault_404', session_user \
+                 WHERE NOT EXISTS (SELECT 1 FROM pii_vault.shred_ledger WHERE key_id = $1)",
+                &[
+                    key_id.clone().into(),
+                    KeyKind::Data.vault_key_name(key_id).into(),
+                    vault.clone().into(),
+                ],
+            )
+            .unwrap_or_else(|e| pgrx::error!("pii_vault: writing shredding ledger failed: {}", e));
+        }
+    });
+
+    REPORTED
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .extend(missing.into_iter().map(|(key_id, _)| key_id));
+}
+
+// Record the erasure of key_id in the ledger and delete its data key from Vault
+// when the transaction commits; returns the ledger entry id. Values sealed with
+// the key can never be decrypted again. A transaction (or savepoint) that rolls
+// back takes the entry with it and leaves the key in Vault, and a deletion that
+// fails at commit aborts the transaction, entry included.
+#[pg_extern(volatile)]
+fn pii_shred(key_id: Option<&[u8]>, reason: default!(Option<&str>, "NULL")) -> i64 {
+    privileges::require_admin("pii_shred");
+    let Some(key_id) = key_id else {
+        pgrx::error!("pii_shred: key_id must not be NULL");
+    };
+
+    let vault_key = KeyKind::Data.vault_key_name(key_id);
+    let vault =
+        vault::location().unwrap_or_else(|| pgrx::error!("Vault error: pii_vault.url is not set"));
+    let performed_by = Spi::get_one::<String>("SELECT current_user::text")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+
+    let id = privileges::as_owner_of("pii_vault.shred_ledger", || {
+        Spi::get_one_with_args::<i64>(
+            "INSERT INTO pii_vault.shred_ledger (key_id, vault_key, vault, source, reason, performed_by) \
+             VALUES ($1, $2, $3, 'shred', $4, $5) RETURNING id",
+            &[
+                key_id.into(),
+                vault_key.into(),
+                vault.into(),
+                reason.into(),
+                performed_by.into(),
+            ],
+        )
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| pgrx::error!("pii_vault: writing shredding ledger failed"))
+    });
+
+    let mut shredded = SHREDDED.lock().unwrap_or_else(|e| e.into_inner());
+    if shredded.is_empty() {
+        register_xact_callback(PgXactCallbackEvent::PreCommit, delete_shredded);
+        register_xact_callback(PgXactCallbackEvent::Abort, || {
+            SHREDDED.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        });
+    }
+    shredded.insert(id, key_id.to_vec());
+    drop(shredded);
+
+    cache::evict(KeyKind::Data, key_id, Eviction::Shredded);
+    id
+}
+
+// Delete the keys shredded by the committing transaction whose ledger entry is
+// still there, i.e. was not rolled back with a savepoint
+fn delete_shredded() {
+    let shredded = std::mem::take(&mut *SHREDDED.lock().unwrap_or_else(|e| e.into_inner()));
+    for (id, key_id) in shredded {
+        let recorded = privileges::as_owner_of("pii_vault.shred_ledger", || {
+            Spi::get_one_with_args::<bool>(
+                "SELECT EXISTS (SELECT 1 FROM pii_vault.shred_ledger WHERE id = $1 AND key_id = $2)",
+                &[id.into(), key_id.clone().into()],
+            )
+            .ok()
+            .flatten()
+            .unwrap_or(false)
+        });
+        if !recorded {
+            continue;
+        }
+
+        if !keys::is_mock() {
+            vault::delete_key(&KeyKind::Data.vault_key_name(&key_id)).unwrap_or_else(|e| {
+                pgrx::error!("Vault error: {}", e);
+            });
+        }
+        // The key may have been fetched again later in the transaction
+        cache::evict(KeyKind::Data, &key_id, Eviction::Shredded);
+    }
+}
+
+extension_sql!(
+    r#"
+CREATE TABLE pii_vault.shred_ledger (
+    id bigint PRIMARY KEY,
+    shredded_at timestamptz NOT NULL,
+    key_id bytea NOT NULL,
+    vault_key text NOT NULL,
+    vault text NOT NULL,
+    source text NOT NULL CHECK (source IN ('shred', 'vault_404')),
+    reason text,
+    performed_by name NOT NULL,
+    prev_hash bytea NOT NULL,
+    row_hash bytea NOT NULL
+);
+CREATE INDEX shred_ledger_key_id_idx ON pii_vault.shred_ledger (key_id);
+REVOKE ALL ON pii_vault.shred_ledger FROM PUBLIC;
+REVOKE EXECUTE ON FUNCTION pii_shred(bytea, text) FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('pii_vault.shred_ledger', '');
+
+-- Canonical form of a ledger row that its hash covers; the timestamp is written
+-- in UTC so the form does not depend on TimeZone or DateStyle
+CREATE FUNCTION pii_vault.shred_ledger_hash(r pii_vault.shred_ledger) RETURNS bytea
+LANGUAGE sql STABLE STRICT
+SET search_path = pg_catalog
+AS $$
+    SELECT sha256(r.prev_hash || convert_to(concat_ws('|',
+        r.id, to_char(r.shredded_at AT TIME ZONE 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS.US'), encode(r.key_id, 'hex'), r.vault_key,
+        r.vault, r.source, coalesce(r.reason, ''), r.performed_by), 'UTF8'));
+$$;
+
+-- Rows are numbered, timestamped and chained here, under a lock, so that
+-- concurrent shreds form one chain in id order. Complete rows, as restored by
+-- pg_dump, are kept as they are; verify_shred_ledger() checks them.
+CREATE FUNCTION pii_vault.shred_ledger_append() RETURNS trigger
+LANGUAGE plpgsql
+SET search_path = pg_catalog
+AS $$
+DECLARE
+    last pii_vault.shred_ledger;
+BEGIN
+    IF NEW.row_hash IS NOT NULL THEN
+        RETURN NEW;
+    END IF;
+
+    PERFORM pg_advisory_xact_lock('pii_vault.shred_ledger'::regclass::oid::bigint);
+    SELECT * INTO last FROM pii_vault.shred_ledger ORDER BY id DESC LIMIT 1;
+
+    NEW.id := coalesce(last.id, 0) + 1;
+    NEW.shredded_at := clock_timestamp();
+    NEW.prev_hash := coalesce(last.row_hash, decode(repeat('00', 32), 'hex'));
+    NEW.row_hash := pii_vault.shred_ledger_hash(NEW);
+    RETURN NEW;
+END;
+$$;
+
+CREATE FUNCTION pii_vault.shred_ledger_immutable() RETURNS trigger
+LANGUAGE plpgsql
+AS $$
+BEGIN
+    RAISE EXCEPTION 'pii_vault.shred_ledger is append-only';
+END;
+$$;
+
+CREATE TRIGGER shred_ledger_append BEFORE INSERT ON pii_vault.shred_ledger
+    FOR EACH ROW EXECUTE FUNCTION pii_vault.shred_ledger_append();
+CREATE TRIGGER shred_ledger_immutable BEFORE UPDATE OR DELETE ON pii_vault.shred_ledger
+    FOR EACH ROW EXECUTE FUNCTION pii_vault.shred_ledger_immutable();
+CREATE TRIGGER shred_ledger_no_truncate BEFORE TRUNCATE ON pii_vault.shred_ledger
+    FOR EACH STATEMENT EXECUTE FUNCTION pii_vault.shred_ledger_immutable();
+
+-- Rows that break the chain; no rows means the ledger is intact
+CREATE FUNCTION pii_vault.verify_shred_ledger() RETURNS TABLE (id bigint, problem text)
+LANGUAGE plpgsql STABLE
+SET search_path = pg_catalog
+AS $$
+DECLARE
+    r pii_vault.shred_ledger;
+    expected_id bigint := 1;
+    expected_prev bytea := decode(repeat('00', 32), 'hex');
+BEGIN
+    FOR r IN SELECT * FROM pii_vault.shred_ledger l ORDER BY l.id LOOP
+        IF r.id <> expected_id THEN
+            id := r.id; problem := format('missing rows %s to %s', expected_id, r.id - 1);
+            RETURN NEXT;
+        END IF;
+        IF r.prev_hash <> expected_prev THEN
+            id := r.id; problem := 'chain broken: prev_hash does not match the previous row';
+            RETURN NEXT;
+        END IF;
+        IF r.row_hash <> pii_vault.shred_ledger_hash(r) THEN
+            id := r.id; problem := 'row modified: row_hash does not match its contents';
+            RETURN NEXT;
+        END IF;
+        expected_id := r.id + 1;
+        expected_prev := r.row_hash;
+    END LOOP;
+END;
+$$;
+
+-- Evidence of the erasure of one subject: its ledger entries, the head of the
+-- chain they are part of, and whether the chain verifies
+CREATE FUNCTION pii_vault.erasure_certificate(key_id bytea) RETURNS jsonb
+LANGUAGE sql STABLE STRICT
+SET search_path = pg_catalog
+AS $$
+    SELECT jsonb_build_object(
+        'subject_key_id', encode(erasure_certificate.key_id, 'hex'),
+        'erased', count(l.id) > 0,
+        'events', coalesce(jsonb_agg(jsonb_build_object(
+                'ledger_id', l.id,
+                'shredded_at', l.shredded_at,
+                'vault_key', l.vault_key,
+                'vault', l.vault,
+                'source', l.source,
+                'reason', l.reason,
+                'performed_by', l.performed_by,
+                'row_hash', encode(l.row_hash, 'hex'))
+            ORDER BY l.id) FILTER (WHERE l.id IS NOT NULL), '[]'::jsonb),
+        'ledger_head', (SELECT jsonb_build_object('ledger_id', h.id, 'row_hash', encode(h.row_hash, 'hex'))
+                        FROM pii_vault.shred_ledger h ORDER BY h.id DESC LIMIT 1),
+        'ledger_verified', NOT EXISTS (SELECT 1 FROM pii_vault.verify_shred_ledger()),
+        'generated_at', now())
+    FROM pii_vault.shred_ledger l
+    WHERE l.key_id = erasure_certificate.key_id;
+$$;
+"#,
+    name = "shred_ledger",
+    requires = ["column_policy", pii_shred]
+);
@@ -0,0 +1,336 @@
+// Activity statistics behind the pg_stat_pii_vault view: encryption and
+// decryption calls, key cache activity, Vault requests by endpoint and status
+// with a latency histogram, auto-created keys, decryption failures by cause and
+// the current run of failed Vault requests.
+//
+// With pg_pii_vault in shared_preload_libraries the counters live in shared
+// memory and cover the whole cluster; otherwise each backend only counts (and
+// sees) its own activity. Every counter is an atomic, so counting on the
+// decryption path never waits for a lock; a reading may mix values from just
+// before and after a concurrent update, as pg_stat views do.
+use crate::privileges;
+use once_cell::sync::Lazy;
+use pgrx::atomics::*;
+use pgrx::pg_shmem_init;
+use pgrx::prelude::*;
+use pgrx::shmem::*;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    Encrypt,
+    Decrypt,
+    CacheHit,
+    CacheMiss,
+    KeyCreated,
+    CacheRefresh,
+}
+
+const COUNTERS: [(Counter, &str); 6] = [
+    (Counter::Encrypt, "encrypt_calls"),
+    (Counter::Decrypt, "decrypt_calls"),
+    (Counter::CacheHit, "cache_hits"),
+    (Counter::CacheMiss, "cache_misses"),
+    (Counter::KeyCreated, "keys_created"),
+    (Counter::CacheRefresh, "cache_refreshes"),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Eviction {
+    // Replaced after pii_vault.cache_ttl_sec
+    Expired,
+    // Dropped by pii_shred(), or found missing in Vault when refreshed
+    Shredded,
+    // Made room under pii_vault.cache_max_entries
+    Capacity,
+    // Dropped by pii_vault_cache_flush() or pii_vault_cache_evict()
+    Flushed,
+}
+
+const EVICTIONS: [(Eviction, &str); 4] = [
+    (Eviction::Expired, "expired"),
+    (Eviction::Shredded, "shredded"),
+    (Eviction::Capacity, "capacity"),
+    (Eviction::Flushed, "flushed"),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    // Vault no longer has the key
+    KeyShredded,
+    // Vault could not be asked or answered with an error
+    Vault,
+    // The value did not authenticate: wrong AAD, tampering or a foreign key
+    Authentication,
+    // Unknown algorithm or undecodable envelope
+    Malformed,
+}
+
+const FAILURES: [(Failure, &str); 4] = [
+    (Failure::KeyShredded, "key_shredded"),
+    (Failure::Vault, "vault_error"),
+    (Failure::Authentication, "authentication"),
+    (Failure::Malformed, "malformed"),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub enum Endpoint {
+    Export,
+    Create,
+    Config,
+    Delete,
+}
+
+const ENDPOINTS: [(Endpoint, &str); 4] = [
+    (Endpoint::Export, "export"),
+    (Endpoint::Create, "create"),
+    (Endpoint::Config, "config"),
+    (Endpoint::Delete, "delete"),
+];
+
+// Status class of a Vault response; None when no response arrived
+const STATUSES: [&str; 5] = ["2xx", "404", "4xx", "5xx", "error"];
+
+fn status_index(status: Option<u16>) -> usize {
+    match status {
+        Some(200..=299) => 0,
+        Some(404) => 1,
+        Some(400..=499) => 2,
+        Some(_) => 3,
+        None => 4,
+    }
+}
+
+// Upper bounds of the latency buckets in milliseconds; the last bucket is +Inf
+const LATENCY_BOUNDS_MS: [u64; 11] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+const LATENCY_BUCKETS: usize = LATENCY_BOUNDS_MS.len() + 1;
+
+#[derive(Debug, Default)]
+struct Stats {
+    counters: [AtomicI64; COUNTERS.len()],
+    evictions: [AtomicI64; EVICTIONS.len()],
+    failures: [AtomicI64; FAILURES.len()],
+    requests: [[AtomicI64; STATUSES.len()]; ENDPOINTS.len()],
+    latency: [[AtomicI64; LATENCY_BUCKETS]; ENDPOINTS.len()],
+    latency_sum_us: [AtomicI64; ENDPOINTS.len()],
+    consecutive_failures: AtomicI64,
+    last_success: AtomicI64,
+    last_failure: AtomicI64,
+    reset_at: AtomicI64,
+}
+
+unsafe impl PGRXSharedMemory for Stats {}
+
+impl Stats {
+    fn reset(&self, now: i64) {
+        let all = self
+            .counters
+            .iter()
+            .chain(&self.evictions)
+            .chain(&self.failures)
+            .chain(self.requests.iter().flatten())
+            .chain(self.latency.iter().flatten())
+            .chain(&self.latency_sum_us)
+            .chain([
+                &self.consecutive_failures,
+                &self.last_success,
+                &self.last_failure,
+            ]);
+        for value in all {
+            value.store(0, Ordering::Relaxed);
+        }
+        self.reset_at.store(now, Ordering::Relaxed);
+    }
+}
+
+static SHARED: PgAtomic<Stats> = unsafe { PgAtomic::new(c"pii_vault_stats") };
+static SHARED_READY: AtomicBool = AtomicBool::new(false);
+static LOCAL: Lazy<Stats> = Lazy::new(|| {
+    let stats = Stats::default();
+    stats.reset_at.store(unix_now(), Ordering::Relaxed);
+    stats
+});
+
+// Called from _PG_init; shared memory can only be requested while preloading
+pub fn init() {
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        pg_shmem_init!(SHARED);
+        SHARED_READY.store(true, Ordering::Relaxed);
+    }
+}
+
+fn stats() -> &'static Stats {
+    if SHARED_READY.load(Ordering::Relaxed) {
+        SHARED.get()
+    } else {
+        &LOCAL
+    }
+}
+
+fn add(value: &AtomicI64, n: i64) {
+    value.fetch_add(n, Ordering::Relaxed);
+}
+
+fn get(value: &AtomicI64) -> i64 {
+    value.load(Ordering::Relaxed)
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn postmaster_start() -> i64 {
+    // PostgreSQL timestamps count microseconds from 2000-01-01
+    unsafe { pg_sys::PgStartTime / 1_000_000 + 946_684_800 }
+}
+
+pub fn counter(counter: Counter) -> i64 {
+    get(&stats().counters[counter as usize])
+}
+
+pub fn count(counter: Counter) {
+    add(&stats().counters[counter as usize], 1);
+}
+
+pub fn count_eviction(reason: Eviction) {
+    add(&stats().evictions[reason as usize], 1);
+}
+
+pub fn count_failure(cause: Failure) {
+    add(&stats().failures[cause as usize], 1);
+}
+
+// One Vault request: its status (None if it failed before a response) and how
+// long it took. Transport errors and 5xx answers extend the failure run, any
+// other answer ends it.
+pub fn vault_request(endpoint: Endpoint, status: Option<u16>, elapsed: Duration) {
+    let ms = elapsed.as_millis() as u64;
+    let bucket = LATENCY_BOUNDS_MS
+        .iter()
+        .position(|bound| ms <= *bound)
+        .unwrap_or(LATENCY_BOUNDS_MS.len());
+    let failed = !matches!(status, Some(0..=499));
+    let now = unix_now();
+
+    let s = stats();
+    let e = endpoint as usize;
+    add(&s.requests[e][status_index(status)], 1);
+    add(&s.latency[e][bucket], 1);
+    add(&s.latency_sum_us[e], elapsed.as_micros() as i64);
+    if failed {
+        add(&s.consecutive_failures, 1);
+        s.last_failure.store(now, Ordering::Relaxed);
+    } else {
+        s.consecutive_failures.store(0, Ordering::Relaxed);
+        s.last_success.store(now, Ordering::Relaxed);
+    }
+}
+
+// Statistics as (name, label, value) rows; labels are comma-separated key=value
+// pairs, empty for unlabeled values. Latency buckets are cumulative.
+pub fn rows() -> Vec<(String, String, i64)> {
+    let stats = stats();
+    let mut rows = Vec::new();
+    let mut push = |name: &str, label: String, value: i64| {
+        rows.push((name.to_string(), label, value));
+    };
+
+    for (counter, name) in COUNTERS {
+        push(name, String::new(), get(&stats.counters[counter as usize]));
+    }
+    for (reason, label) in EVICTIONS {
+        push(
+            "cache_evictions",
+            format!("reason={}", label),
+            get(&stats.evictions[reason as usize]),
+        );
+    }
+    for (cause, label) in FAILURES {
+        push(
+            "decrypt_failures",
+            format!("cause={}", label),
+            get(&stats.failures[cause as usize]),
+        );
+    }
+    for (endpoint, endpoint_name) in ENDPOINTS {
+        let e = endpoint as usize;
+        for (i, status) in STATUSES.iter().enumerate() {
+            push(
+                "vault_requests",
+                format!("endpoint={},status={}", endpoint_name, status),
+                get(&stats.requests[e][i]),
+            );
+        }
+        let mut cumulative = 0;
+        for (i, count) in stats.latency[e].iter().enumerate() {
+            cumulative += get(count);
+            let le = match LATENCY_BOUNDS_MS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            push(
+                "vault_latency_ms_bucket",
+                format!("endpoint={},le={}", endpoint_name, le),
+                cumulative,
+            );
+        }
+        push(
+            "vault_latency_us_sum",
+            format!("endpoint={}", endpoint_name),
+            get(&stats.latency_sum_us[e]),
+        );
+    }
+    push(
+        "vault_consecutive_failures",
+        String::new(),
+        get(&stats.consecutive_failures),
+    );
+    push(
+        "vault_last_success",
+        String::new(),
+        get(&stats.last_success),
+    );
+    push(
+        "vault_last_failure",
+        String::new(),
+        get(&stats.last_failure),
+    );
+    // Never reset since the postmaster started
+    let reset_at = match get(&stats.reset_at) {
+        0 => postmaster_start(),
+        reset_at => reset_at,
+    };
+    push("stats_reset", String::new(), reset_at);
+    push(
+        "shared_memory",
+        String::new(),
+        SHARED_READY.load(Ordering::Relaxed) as i64,
+    );
+    rows
+}
+
+#[pg_extern(volatile)]
+fn pg_stat_pii_vault(
+) -> TableIterator<'static, (name!(name, String), name!(label, String), name!(value, i64))> {
+    TableIterator::new(rows())
+}
+
+// Zero all counters, like pg_stat_reset()
+#[pg_extern(volatile)]
+fn pg_stat_pii_vault_reset() {
+    privileges::require_admin("pg_stat_pii_vault_reset");
+    stats().reset(unix_now());
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW pg_stat_pii_vault AS SELECT * FROM pg_stat_pii_vault();
+REVOKE EXECUTE ON FUNCTION pg_stat_pii_vault_reset() FROM PUBLIC;
+"#,
+    name = "pg_stat_pii_vault_view",
+    requires = [pg_stat_pii_vault, pg_stat_pii_vault_reset]
+);
@@ -0,0 +1,109 @@
+// Reversible tokenization for analytics exports: pii_tokenize() replaces a value
+// by a short opaque token, stable per namespace (an HMAC of the value under the
+// namespace's Vault key), and records the value sealed with the subject's key in
+// pii_vault.token_map. pii_detokenize() is not executable by PUBLIC and returns
+// NULL once the subject key has been shredded or the mapping forgotten. Nor are
+// pii_tokenize() and pii_token(): anyone able to compute tokens could match
+// exported tokens against guessed values, and pii_tokenize() writes mappings
+// and creates Vault keys as its owner.
+use crate::keys::{self, KeyKind};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use pgrx::prelude::*;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// 128-bit tokens: collisions within a namespace are not a practical concern
+const TOKEN_BYTES: usize = 16;
+
+// Token of value in namespace, without recording the mapping
+#[pg_extern(immutable, strict, parallel_safe)]
+fn pii_token(value: &str, namespace: &str) -> String {
+    let key = keys::resolve(KeyKind::Token, namespace.as_bytes()).unwrap_or_else(|e| {
+        pgrx::error!("Vault error: {}", e);
+    });
+
+    let mut mac = HmacSha256::new_from_slice(&key[..]).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    format!("tok_{}", URL_SAFE_NO_PAD.encode(&digest[..TOKEN_BYTES]))
+}
+
+// Without a subject key_id (also a NULL one) the mapping is sealed with the
+// data key of the namespace itself (key_id 'tok:<namespace>'), which shreds all
+// of its tokens. A NULL value has no token.
+extension_sql!(
+    r#"
+CREATE TABLE pii_vault.token_map (
+    namespace text NOT NULL,
+    token text NOT NULL,
+    key_id bytea NOT NULL,
+    value piitext NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (namespace, token)
+);
+CREATE INDEX token_map_key_id_idx ON pii_vault.token_map (key_id);
+REVOKE ALL ON pii_vault.token_map FROM PUBLIC;
+SELECT pg_catalog.pg_extension_config_dump('pii_vault.token_map', '');
+
+CREATE FUNCTION pii_tokenize(value text, namespace text, key_id bytea DEFAULT NULL) RETURNS text
+LANGUAGE plpgsql SECURITY DEFINER
+SET search_path = pg_catalog, @extschema@
+AS $$
+DECLARE
+    token text;
+    subject bytea;
+BEGIN
+    IF namespace IS NULL THEN
+        RAISE EXCEPTION 'pii_tokenize: namespace must not be NULL';
+    END IF;
+    IF value IS NULL THEN
+        RETURN NULL;
+    END IF;
+    token := pii_token(value, namespace);
+    subject := coalesce(key_id, convert_to('tok:' || namespace, 'UTF8'));
+
+    -- A mapping that can no longer be opened (its subject was shredded) is
+    -- taken over by the new subject
+    INSERT INTO pii_vault.token_map AS m (namespace, token, key_id, value)
+    VALUES (namespace, token, subject,
+            piitext_encrypt_bound(value, subject, 'pii_vault.token_map', namespace))
+    ON CONFLICT ON CONSTRAINT token_map_pkey DO UPDATE
+        SET key_id = EXCLUDED.key_id, value = EXCLUDED.value, created_at = now()
+        WHERE piitext_try_out_text(m.value) IS NULL;
+    RETURN token;
+END;
+$$;
+
+CREATE FUNCTION pii_detokenize(token text, namespace text) RETURNS text
+LANGUAGE plpgsql STRICT STABLE SECURITY DEFINER
+SET search_path = pg_catalog, @extschema@
+AS $$
+BEGIN
+    RETURN (SELECT piitext_try_out_text(m.value)
+            FROM pii_vault.token_map m
+            WHERE m.namespace = pii_detokenize.namespace AND m.token = pii_detokenize.token);
+END;
+$$;
+REVOKE EXECUTE ON FUNCTION pii_detokenize(text, text) FROM PUBLIC;
+REVOKE EXECUTE ON FUNCTION pii_tokenize(text, text, bytea) FROM PUBLIC;
+REVOKE EXECUTE ON FUNCTION pii_token(text, text) FROM PUBLIC;
+
+-- Drop the mappings of a subject, invalidating its tokens without touching Vault
+CREATE FUNCTION pii_vault.forget_tokens(key_id bytea) RETURNS bigint
+LANGUAGE sql STRICT
+SET search_path = pg_catalog, @extschema@
+AS $$
+    WITH forgotten AS (
+        DELETE FROM pii_vault.token_map m WHERE m.key_id = forget_tokens.key_id RETURNING 1
+    )
+    SELECT count(*) FROM forgotten;
+$$;
+REVOKE EXECUTE ON FUNCTION pii_vault.forget_tokens(bytea) FROM PUBLIC;
+"#,
+    name = "token_map",
+    requires = ["column_policy", "piitext_casts", pii_token]
+);
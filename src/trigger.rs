@@ -0,0 +1,190 @@
+use crate::contents::PiiTextContents;
+use crate::{crypto, key_id, seal_piitext, seal_piitext_with, PiiText};
+use pgrx::pg_sys::PgBuiltInOids;
+use pgrx::prelude::*;
+use pgrx::{AllocatedByRust, PgOid};
+
+// Seals staging piitext values with the key of the row's subject:
+//
+//   CREATE TRIGGER users_pii BEFORE INSERT OR UPDATE ON users
+//       FOR EACH ROW EXECUTE FUNCTION pii_vault_encrypt_trigger('id', 'email', 'phone');
+//
+// The first argument names the subject-id column, the rest name piitext columns.
+// Arguments of the form option=value are options rather than columns:
+//
+//   namespace=<ns>    seal with key_id "<ns>:" || canonical subject id
+//   relation=<label>  bind the AAD to a relation label
+//   attribute=<label> bind the AAD to an attribute label
+//   algorithm=<alg>   aes-256-gcm (default) or aes-256-gcm-siv; the latter
+//                     seals deterministically under the shared key of the
+//                     namespace (required) instead of the subject's key
+//
+// Already sealed values are left untouched, except that a value bound to other
+// labels than the configured ones is rejected (it was copied from another column).
+// Unbound values sealed before the binding was configured are accepted.
+#[pg_trigger]
+fn pii_vault_encrypt_trigger<'a>(
+    trigger: &'a PgTrigger<'a>,
+) -> Result<Option<PgHeapTuple<'a, impl WhoAllocated>>, PgHeapTupleError> {
+    if !matches!(trigger.when(), Ok(PgTriggerWhen::Before)) {
+        pgrx::error!(
+            "pii_vault_encrypt_trigger must be fired BEFORE INSERT OR UPDATE FOR EACH ROW"
+        );
+    }
+
+    let args = trigger.extra_args().unwrap_or_else(|e| {
+        pgrx::error!("pii_vault_encrypt_trigger: {}", e);
+    });
+    let (options, args): (Vec<&String>, Vec<&String>) =
+        args.iter().partition(|arg| arg.contains('='));
+    let Some((subject_column, columns)) = args.split_first().filter(|(_, c)| !c.is_empty()) else {
+        pgrx::error!(
+            "pii_vault_encrypt_trigger expects arguments (subject_column, piitext_column [, ...])"
+        );
+    };
+
+    let mut namespace = None;
+    let mut relation = None;
+    let mut attribute = None;
+    let mut deterministic = false;
+    for option in options {
+        match option.split_once('=') {
+            Some(("namespace", value)) => namespace = Some(value),
+            Some(("relation", value)) => relation = Some(value),
+            Some(("attribute", value)) => attribute = Some(value),
+            Some(("algorithm", "aes-256-gcm")) => deterministic = false,
+            Some(("algorithm", "aes-256-gcm-siv")) => deterministic = true,
+            _ => pgrx::error!("pii_vault_encrypt_trigger: unknown option \"{}\"", option),
+        }
+    }
+
+    if deterministic && namespace.is_none() {
+        pgrx::error!("pii_vault_encrypt_trigger: algorithm=aes-256-gcm-siv requires a namespace");
+    }
+
+    let mut new = match trigger.new() {
+        Some(tuple) => tuple.into_owned(),
+        None => pgrx::error!(
+            "pii_vault_encrypt_trigger must be fired BEFORE INSERT OR UPDATE FOR EACH ROW"
+        ),
+    };
+
+    let mut subject_key_id = None;
+    for column in columns {
+        let value = new.get_by_name::<PiiText>(column).unwrap_or_else(|e| {
+            pgrx::error!("pii_vault_encrypt_trigger: column \"{}\": {}", column, e);
+        });
+
+        let Some(value) = value else { continue };
+        let plaintext = match PiiTextContents::from(value.inner.as_slice()) {
+            PiiTextContents::Staging(s) => s.into_owned(),
+            PiiTextContents::Sealed(sealed) => {
+                let bound = sealed.relation.is_some() || sealed.attribute.is_some();
+                if bound
+                    && (sealed.relation.as_deref() != relation
+                        || sealed.attribute.as_deref() != attribute)
+                {
+                    pgrx::error!(
+                        "pii_vault_encrypt_trigger: value for column \"{}\" is sealed for another column",
+                        column
+                    );
+                }
+                continue;
+            }
+        };
+
+        if let (true, Some(ns)) = (deterministic, namespace) {
+            let sealed = seal_piitext_with(
+                &plaintext,
+                ns.as_bytes().to_vec(),
+                crypto::ALG_AES_256_GCM_SIV,
+                relation,
+                attribute,
+            );
+            set_column(&mut new, column, sealed);
+            continue;
+        }
+
+        let key_id_bytes = subject_key_id
+            .get_or_insert_with(|| {
+                let key_id_bytes = subject_key_id_of(&new, subject_column);
+                match namespace {
+                    Some(ns) => key_id::namespaced(ns, key_id_bytes),
+                    None => key_id_bytes,
+                }
+            })
+            .clone();
+        set_column(
+            &mut new,
+            column,
+            seal_piitext(&plaintext, key_id_bytes, relation, attribute),
+        );
+    }
+
+    Ok(Some(new))
+}
+
+// Canonical key_id (see key_id.rs) of the subject-id column of the row
+fn subject_key_id_of(tuple: &PgHeapTuple<'_, impl WhoAllocated>, column: &str) -> Vec<u8> {
+    let Some((_, att)) = tuple.get_attribute_by_name(column) else {
+        pgrx::error!(
+            "pii_vault_encrypt_trigger: subject column \"{}\" does not exist",
+            column
+        );
+    };
+
+    let key_id_bytes = match PgOid::from(att.atttypid) {
+        PgOid::BuiltIn(PgBuiltInOids::INT2OID) => tuple
+            .get_by_name::<i16>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(|id| key_id::from_int4(id as i32)),
+        PgOid::BuiltIn(PgBuiltInOids::INT4OID) => tuple
+            .get_by_name::<i32>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(key_id::from_int4),
+        PgOid::BuiltIn(PgBuiltInOids::INT8OID) => tuple
+            .get_by_name::<i64>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(key_id::from_int8),
+        PgOid::BuiltIn(PgBuiltInOids::UUIDOID) => tuple
+            .get_by_name::<pgrx::Uuid>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(|id| key_id::from_uuid(&id)),
+        PgOid::BuiltIn(PgBuiltInOids::TEXTOID)
+        | PgOid::BuiltIn(PgBuiltInOids::VARCHAROID)
+        | PgOid::BuiltIn(PgBuiltInOids::BPCHAROID) => tuple
+            .get_by_name::<String>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(|id| key_id::from_text(&id)),
+        PgOid::BuiltIn(PgBuiltInOids::BYTEAOID) => tuple
+            .get_by_name::<&[u8]>(column)
+            .unwrap_or_else(|e| fetch_error(column, e))
+            .map(<[u8]>::to_vec),
+        _ => pgrx::error!(
+            "pii_vault_encrypt_trigger: subject column \"{}\" must be int2, int4, int8, uuid, text or bytea",
+            column
+        ),
+    };
+
+    // Refuse to store plaintext rather than silently leaving it unsealed
+    key_id_bytes.unwrap_or_else(|| {
+        pgrx::error!(
+            "pii_vault_encrypt_trigger: subject column \"{}\" is NULL, cannot seal PII",
+            column
+        );
+    })
+}
+
+fn set_column(tuple: &mut PgHeapTuple<'_, AllocatedByRust>, column: &str, value: PiiText) {
+    tuple.set_by_name(column, value).unwrap_or_else(|e| {
+        pgrx::error!("pii_vault_encrypt_trigger: column \"{}\": {}", column, e);
+    });
+}
+
+fn fetch_error(column: &str, e: impl std::fmt::Display) -> ! {
+    pgrx::error!(
+        "pii_vault_encrypt_trigger: subject column \"{}\": {}",
+        column,
+        e
+    );
+}
@@ -1,6 +1,16 @@
+use crate::keys::Exported;
+use crate::secure::SecureKey;
+use crate::stats::{self, Counter, Endpoint};
 use crate::{PII_VAULT_MOUNT, PII_VAULT_TOKEN, PII_VAULT_URL};
 use base64::{engine::general_purpose, Engine as _};
+use reqwest::blocking::{RequestBuilder, Response};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Deserialize)]
 struct VaultExportResponse {
@@ -12,7 +22,13 @@ struct VaultExportData {
     keys: std::collections::HashMap<String, String>,
 }
 
-pub fn get_key_from_vault(key_id: &[u8]) -> Result<[u8; 32], String> {
+struct VaultConfig {
+    url: String,
+    token: String,
+    mount: String,
+}
+
+fn config() -> Result<VaultConfig, String> {
     let url_guc = PII_VAULT_URL.get().ok_or("pii_vault.url is not set")?;
     let token_guc = PII_VAULT_TOKEN.get().ok_or("pii_vault.token is not set")?;
     let mount_guc = PII_VAULT_MOUNT.get();
@@ -23,72 +39,287 @@ pub fn get_key_from_vault(key_id: &[u8]) -> Result<[u8; 32], String> {
     let token = token_guc
         .to_str()
         .map_err(|e: std::str::Utf8Error| e.to_string())?;
-    let mount_str;
     let mount = match &mount_guc {
-        Some(m) => {
-            mount_str = m.to_str().map_err(|e: std::str::Utf8Error| e.to_string())?;
-            mount_str
-        }
+        Some(m) => m.to_str().map_err(|e: std::str::Utf8Error| e.to_string())?,
         None => "transit",
     };
 
-    let key_name = hex::encode(key_id);
-    let full_url = format!("{}/v1/{}/export/encryption-key/{}", url, mount, key_name);
+    Ok(VaultConfig {
+        url: url.to_string(),
+        token: token.to_string(),
+        mount: mount.to_string(),
+    })
+}
+
+// Whether pii_vault.url and pii_vault.token are set
+pub fn configured() -> bool {
+    config().is_ok()
+}
+
+// Identifies where keys come from and who may read them: a digest of
+// pii_vault.url, pii_vault.mount and pii_vault.token. The same key_id names a
+// different key on another Vault or Transit mount, and a token without access
+// to a key must not be served a copy exported with another token, so cached
+// keys are only served for the provider they were exported from.
+pub type Provider = [u8; 16];
+
+pub fn provider() -> Option<Provider> {
+    let url = PII_VAULT_URL.get()?;
+    let token = PII_VAULT_TOKEN.get()?;
+    let mount = PII_VAULT_MOUNT.get();
+    let mut hasher = Sha256::new();
+    hasher.update(url.as_bytes());
+    hasher.update([0u8]);
+    hasher.update(mount.as_deref().map_or(&b"transit"[..], |m| m.to_bytes()));
+    hasher.update([0u8]);
+    hasher.update(Sha256::digest(token.to_bytes()));
+    let mut provider = Provider::default();
+    provider.copy_from_slice(&hasher.finalize()[..16]);
+    Some(provider)
+}
+
+// pii_vault.url and pii_vault.mount in readable form, as the shredding ledger
+// records them
+pub fn location() -> Option<String> {
+    let url = PII_VAULT_URL.get()?;
+    let mount = PII_VAULT_MOUNT.get();
+    Some(format!(
+        "{}/{}",
+        url.to_string_lossy().trim_end_matches('/'),
+        mount
+            .as_deref()
+            .map_or("transit".into(), |m| m.to_string_lossy())
+    ))
+}
+
+// Send a request to Vault, recording its status and latency in the statistics
+fn send(endpoint: Endpoint, request: RequestBuilder) -> reqwest::Result<Response> {
+    let started = Instant::now();
+    let result = request.send();
+    let status = result.as_ref().ok().map(|r| r.status().as_u16());
+    stats::vault_request(endpoint, status, started.elapsed());
+    result
+}
+
+// Export a named Transit key, creating it if it does not exist yet; export_type
+// is "encryption-key" or "hmac-key"
+pub fn export_key(key_name: &str, export_type: &str) -> Result<SecureKey, String> {
+    let config = config()?;
+    if let Some(key) = fetch_export(&config, key_name, export_type)? {
+        return Ok(key);
+    }
+
+    create_key_in_vault(&config.url, &config.token, &config.mount, key_name)?;
+    stats::count(Counter::KeyCreated);
+    fetch_export(&config, key_name, export_type)?
+        .ok_or_else(|| format!("Vault key {} not found after creating it", key_name))
+}
+
+// Export a named Transit key for decryption: None if it does not exist (it has
+// been shredded), since creating a new key could never decrypt old values
+pub fn export_existing_key(key_name: &str, export_type: &str) -> Result<Option<SecureKey>, String> {
+    fetch_export(&config()?, key_name, export_type)
+}
 
+fn fetch_export(
+    config: &VaultConfig,
+    key_name: &str,
+    export_type: &str,
+) -> Result<Option<SecureKey>, String> {
     let client = reqwest::blocking::Client::new();
-    let resp = client
+    finish_export(request_export(&client, config, key_name, export_type))
+}
+
+// Outcome of an export request. Building it touches nothing of PostgreSQL, so
+// prefetching can run requests on several threads; finish_export() then records
+// the statistics and moves the key into protected memory on the backend thread.
+struct Export {
+    status: Option<u16>,
+    elapsed: Duration,
+    key: Result<Option<Zeroizing<Vec<u8>>>, String>,
+}
+
+fn request_export(
+    client: &reqwest::blocking::Client,
+    config: &VaultConfig,
+    key_name: &str,
+    export_type: &str,
+) -> Export {
+    let full_url = format!(
+        "{}/v1/{}/export/{}/{}",
+        config.url, config.mount, export_type, key_name
+    );
+
+    let started = Instant::now();
+    let result = client
         .get(&full_url)
-        .header("X-Vault-Token", token)
-        .send()
-        .map_err(|e| format!("Vault request failed: {}", e))?;
+        .header("X-Vault-Token", &config.token)
+        .send();
+    let status = result.as_ref().ok().map(|r| r.status().as_u16());
+    let key = result
+        .map_err(|e| format!("Vault request failed: {}", e))
+        .and_then(read_export);
+    Export {
+        status,
+        elapsed: started.elapsed(),
+        key,
+    }
+}
+
+// Export responses are a few hundred bytes; a larger body is refused rather
+// than read into a growing buffer, whose reallocations would leave unwiped
+// copies of the key behind
+const MAX_EXPORT_BODY: usize = 64 * 1024;
 
+fn read_export(mut resp: Response) -> Result<Option<Zeroizing<Vec<u8>>>, String> {
     if resp.status() == reqwest::StatusCode::NOT_FOUND {
-        // Try to create key if not found
-        create_key_in_vault(url, token, mount, &key_name)?;
-        // Retry export
-        return get_key_from_vault(key_id);
+        return Ok(None);
     }
 
     if !resp.status().is_success() {
         return Err(format!("Vault returned error: {}", resp.status()));
     }
 
-    let export_resp: VaultExportResponse = resp
-        .json()
+    // The body is read straight into a buffer that is wiped, like the decoded
+    // key, once the key is in protected memory. The HTTP client's own buffers
+    // are outside our reach.
+    let mut body = Zeroizing::new(Vec::with_capacity(MAX_EXPORT_BODY + 1));
+    (&mut resp)
+        .take(MAX_EXPORT_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("Failed to read Vault response: {}", e))?;
+    if body.len() > MAX_EXPORT_BODY {
+        return Err("Vault response is too large".to_string());
+    }
+    let mut export_resp: VaultExportResponse = serde_json::from_slice(&body)
         .map_err(|e| format!("Failed to parse Vault response: {}", e))?;
 
     // Transit export returns keys in a map, version as key
-    let latest_key_base64 = export_resp
+    let key_bytes = export_resp
         .data
         .keys
         .values()
         .next()
-        .ok_or("No key found in Vault response")?;
-    let key_bytes = general_purpose::STANDARD
-        .decode(latest_key_base64)
-        .map_err(|e| format!("Failed to decode key: {}", e))?;
+        .ok_or("No key found in Vault response")
+        .map(|encoded| {
+            general_purpose::STANDARD
+                .decode(encoded)
+                .map(Zeroizing::new)
+        });
+    export_resp.data.keys.values_mut().for_each(|v| v.zeroize());
+    let key_bytes = key_bytes?.map_err(|e| format!("Failed to decode key: {}", e))?;
+    Ok(Some(key_bytes))
+}
+
+fn finish_export(export: Export) -> Result<Option<SecureKey>, String> {
+    stats::vault_request(Endpoint::Export, export.status, export.elapsed);
+    match export.key? {
+        Some(key_bytes) => SecureKey::from_slice(&key_bytes)
+            .map(Some)
+            .ok_or_else(|| format!("Invalid key length: {}", key_bytes.len())),
+        None => Ok(None),
+    }
+}
+
+// export_existing_key() for many keys at once, with up to `parallelism`
+// requests in flight; results are in the order of `keys`
+pub fn export_existing_keys(
+    keys: &[(String, &str)],
+    parallelism: usize,
+) -> Result<Exported, String> {
+    let config = config()?;
+    let client = reqwest::blocking::Client::new();
+    let exports = fan_out(keys.len(), parallelism, |i| {
+        let (key_name, export_type) = &keys[i];
+        request_export(&client, &config, key_name, export_type)
+    });
+    Ok(exports.into_iter().map(finish_export).collect())
+}
+
+// request(i) for every i in 0..n on up to `parallelism` threads; results are in
+// the order of i. request must not touch PostgreSQL.
+fn fan_out<T: Send>(n: usize, parallelism: usize, request: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<T>>> = (0..n).map(|_| Mutex::new(None)).collect();
+
+    std::thread::scope(|scope| {
+        for _ in 0..parallelism.clamp(1, n.max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                if i >= n {
+                    break;
+                }
+                let result = request(i);
+                *results[i].lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+            });
+        }
+    });
 
-    if key_bytes.len() != 32 {
-        return Err(format!("Invalid key length: {}", key_bytes.len()));
+    results
+        .into_iter()
+        .map(|result| {
+            let result = result.into_inner().unwrap_or_else(|e| e.into_inner());
+            result.expect("every request was made")
+        })
+        .collect()
+}
+
+// Delete a Transit key for good. Transit refuses to delete keys unless
+// deletion_allowed is set on them first.
+pub fn delete_key(key_name: &str) -> Result<(), String> {
+    let config = config()?;
+    let key_url = format!("{}/v1/{}/keys/{}", config.url, config.mount, key_name);
+    let client = reqwest::blocking::Client::new();
+
+    let resp = send(
+        Endpoint::Config,
+        client
+            .post(format!("{}/config", key_url))
+            .header("X-Vault-Token", &config.token)
+            .json(&serde_json::json!({ "deletion_allowed": true })),
+    )
+    .map_err(|e| format!("Vault key config request failed: {}", e))?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        // Already gone
+        return Ok(());
+    }
+    if !resp.status().is_success() {
+        return Err(format!(
+            "Vault key config returned error: {}",
+            resp.status()
+        ));
     }
 
-    let mut key = [0u8; 32];
-    key.copy_from_slice(&key_bytes);
-    Ok(key)
+    let resp = send(
+        Endpoint::Delete,
+        client
+            .delete(&key_url)
+            .header("X-Vault-Token", &config.token),
+    )
+    .map_err(|e| format!("Vault delete key request failed: {}", e))?;
+    if !resp.status().is_success() && resp.status() != reqwest::StatusCode::NOT_FOUND {
+        return Err(format!(
+            "Vault delete key returned error: {}",
+            resp.status()
+        ));
+    }
+    Ok(())
 }
 
 fn create_key_in_vault(url: &str, token: &str, mount: &str, key_name: &str) -> Result<(), String> {
     let full_url = format!("{}/v1/{}/keys/{}", url, mount, key_name);
     let client = reqwest::blocking::Client::new();
-    let resp = client
-        .post(&full_url)
-        .header("X-Vault-Token", token)
-        .json(&serde_json::json!({
-            "type": "aes256-gcm96",
-            "exportable": true
-        }))
-        .send()
-        .map_err(|e| format!("Vault create key request failed: {}", e))?;
+    let resp = send(
+        Endpoint::Create,
+        client
+            .post(&full_url)
+            .header("X-Vault-Token", token)
+            .json(&serde_json::json!({
+                "type": "aes256-gcm96",
+                "exportable": true
+            })),
+    )
+    .map_err(|e| format!("Vault create key request failed: {}", e))?;
 
     if !resp.status().is_success() {
         return Err(format!(
@@ -98,3 +329,33 @@ fn create_key_in_vault(url: &str, token: &str, mount: &str, key_name: &str) -> R
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_out_keeps_order_and_bounds_parallelism() {
+        let calls = AtomicUsize::new(0);
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let results = fan_out(20, 4, |i| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            i * 2
+        });
+
+        assert_eq!(results, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(calls.load(Ordering::SeqCst), 20);
+        let peak = peak.load(Ordering::SeqCst);
+        assert!(peak > 1 && peak <= 4, "peak of {} requests in flight", peak);
+    }
+
+    #[test]
+    fn fan_out_runs_at_least_one_thread() {
+        assert!(fan_out(0, 8, |i| i).is_empty());
+        assert_eq!(fan_out(3, 0, |i| i), vec![0, 1, 2]);
+    }
+}